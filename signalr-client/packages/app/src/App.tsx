@@ -7,12 +7,12 @@ import {ChatClient} from 'signalr-wasm';
 import { generate } from 'random-words';
 
 const myUser = generate() as string;
-const client = new ChatClient("ws://localhost:5095/chatHub", myUser);
+const client = new ChatClient("http://localhost:5095/chatHub", myUser);
 
 let didInit = false;
 
 function App() {
-  const [loading, setLoading] = useState(true);
+  const [connectionState, setConnectionState] = useState("Disconnected");
   const [error, setError] = useState("");
   const [messages, setMessages] = useState<{ user: string, message: string }[]>([]);
 
@@ -30,6 +30,8 @@ function App() {
 
       didInit = true;
 
+      client.on_state_changed(setConnectionState);
+
       try {
         await client.connect();
         client.on_message_received(onMessage)
@@ -37,8 +39,6 @@ function App() {
       } catch (e) {
         console.error(e);
         setError(e as string);
-      } finally {
-        setLoading(false);
       }
     }
 
@@ -74,7 +74,7 @@ function App() {
       <form onSubmit={handleSubmit} style={{display: 'flex', flexDirection: 'column', gap: '1rem'}}>
         <label htmlFor="message">Enter a message</label>
         <input name="message" required id="message"/>
-        <button type="submit" disabled={loading}>Send</button>
+        <button type="submit" disabled={connectionState !== "Connected"}>Send</button>
       </form>
     </>)
 }
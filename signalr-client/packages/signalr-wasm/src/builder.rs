@@ -0,0 +1,565 @@
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::connection::SignalRConnection;
+use crate::log::{LogLevel, Logger};
+use crate::reconnect::{DefaultReconnectPolicy, ReconnectPolicy};
+use crate::runtime::{self, Runtime};
+use crate::trace::TraceProvider;
+#[cfg(not(feature = "native"))]
+use crate::transport::{constructor_factory, WebSocketFactory};
+use crate::transport::{Transport, TransportFactory};
+use crate::url::HubUrl;
+
+/// Produces headers to add to an outgoing hub message.
+pub type MessageHeadersFactory = Rc<dyn Fn() -> HashMap<String, String>>;
+
+/// Produces an access token. The argument is `true` when the server rejected the previous
+/// token, so a cached token should not be returned again.
+pub type AccessTokenFactory = Rc<dyn Fn(bool) -> LocalBoxFuture<'static, Result<String, String>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HubProtocol {
+    Json,
+}
+
+impl HubProtocol {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HubProtocol::Json => "json",
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        match self {
+            HubProtocol::Json => 1,
+        }
+    }
+}
+
+impl std::str::FromStr for HubProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(HubProtocol::Json),
+            other => Err(format!("Unsupported hub protocol `{}`", other)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportType {
+    WebSockets,
+    ServerSentEvents,
+    LongPolling,
+}
+
+impl std::str::FromStr for TransportType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "websockets" => Ok(TransportType::WebSockets),
+            "serversentevents" => Ok(TransportType::ServerSentEvents),
+            "longpolling" => Ok(TransportType::LongPolling),
+            other => Err(format!("Unknown transport `{}`", other)),
+        }
+    }
+}
+
+/// What happens when the server sends invocations faster than a handler or stream consumes
+/// them and its queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop reading from the server until there's room, holding up every other subscription.
+    #[default]
+    Block,
+    /// Discard the oldest queued invocation.
+    DropOldest,
+    /// Discard the invocation that didn't fit.
+    DropNewest,
+    /// Close the connection with [`SignalRError::Overflow`](crate::SignalRError::Overflow).
+    Disconnect,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "block" => Ok(OverflowPolicy::Block),
+            "dropoldest" => Ok(OverflowPolicy::DropOldest),
+            "dropnewest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            other => Err(format!("Unknown overflow policy `{}`", other)),
+        }
+    }
+}
+
+/// How invocations waiting for a handler or stream are queued. Connections default to
+/// [`ConnectionOptions::channel_capacity`] and [`ConnectionOptions::overflow_policy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubscriptionOptions {
+    pub capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+}
+
+/// Buffers invocations made while connecting or reconnecting, to be sent once connected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OfflineQueue {
+    /// How many invocations may wait at once. Further ones fail with
+    /// [`SignalRError::QueueFull`](crate::SignalRError::QueueFull).
+    pub max_size: usize,
+    /// How long each invocation may wait before failing with
+    /// [`SignalRError::QueueExpired`](crate::SignalRError::QueueExpired).
+    pub expiry: Duration,
+}
+
+/// Everything that can be configured on a [`SignalRConnection`]. Usually built
+/// through [`HubConnectionBuilder`] rather than by hand.
+#[derive(Clone)]
+pub struct ConnectionOptions {
+    /// The hub's URL, with an `http`, `https`, `ws` or `wss` scheme, or relative to the page.
+    pub url: String,
+    pub protocol: HubProtocol,
+    /// Transports the connection may use, in order of preference.
+    pub transports: Vec<TransportType>,
+    /// Called before every connection attempt, including reconnects. The token is sent in the
+    /// `Authorization` header, and also as the `access_token` query parameter when opening a
+    /// WebSocket outside the native backend, since browsers can't set headers on one.
+    pub access_token_factory: Option<AccessTokenFactory>,
+    /// Connect straight to the WebSocket instead of asking the server's negotiate endpoint
+    /// first. Possible because WebSockets are the only supported transport. On by default,
+    /// in which case there's no negotiate request to be rejected with a 401, and expired
+    /// tokens are only noticed when the server closes the socket; see
+    /// [`SignalRError::is_auth_failure`](crate::SignalRError::is_auth_failure).
+    pub skip_negotiation: bool,
+    /// How many times to refresh the access token and try again when the server rejects it,
+    /// either by failing the negotiate request with a 401 or by closing the socket.
+    pub token_refresh_attempts: u32,
+    /// Sent with HTTP requests made by the connection. Browsers do not allow custom headers
+    /// on WebSocket upgrades, but the native backend and custom WebSocket constructors get
+    /// them.
+    pub headers: HashMap<String, String>,
+    /// Creates WebSockets instead of the global `WebSocket`, for JavaScript runtimes without
+    /// one, tests or instrumentation.
+    #[cfg(not(feature = "native"))]
+    pub websocket_factory: Option<WebSocketFactory>,
+    /// Creates the transport for each connection attempt. `None` uses
+    /// [`WebSocketTransport`](crate::WebSocketTransport).
+    pub transport_factory: Option<TransportFactory>,
+    /// Spawns the connection's tasks and drives its timers. `None` uses
+    /// [`default_runtime`](crate::default_runtime).
+    pub runtime: Option<Rc<dyn Runtime>>,
+    /// `None` disables automatic reconnects.
+    pub reconnect_policy: Option<Rc<dyn ReconnectPolicy>>,
+    pub handshake_timeout: Duration,
+    /// How long to wait for any message from the server before considering it gone.
+    pub server_timeout: Duration,
+    /// How often to ping the server when nothing else has been sent.
+    pub keep_alive_interval: Duration,
+    /// Messages below this level aren't logged.
+    pub log_level: LogLevel,
+    /// Receives the connection's log messages. `None` uses
+    /// [`DefaultLogger`](crate::DefaultLogger).
+    pub logger: Option<Rc<dyn Logger>>,
+    /// Capacity of the channels between the socket and subscribers. The native backend also
+    /// bounds its queue of received frames with it; a JavaScript `WebSocket` can't be paused,
+    /// so there every frame is queued.
+    pub channel_capacity: usize,
+    /// What subscribers do when their channel is full.
+    pub overflow_policy: OverflowPolicy,
+    /// `None` fails invocations made while not connected straight away.
+    pub offline_queue: Option<OfflineQueue>,
+    /// Called for every invocation and completion the connection sends, adding the headers it
+    /// returns to the message. Headers set on the message itself take precedence.
+    pub message_headers: Option<MessageHeadersFactory>,
+    /// Starts a span for each invocation and propagates it to the server. `None` disables
+    /// tracing.
+    pub trace_provider: Option<Rc<dyn TraceProvider>>,
+    /// How many times an idempotent invocation is sent again when the connection is lost
+    /// before its result arrives and then reestablished.
+    pub invocation_retries: u32,
+    /// Prefixes the idempotency keys of this connection's invocations. `None` picks a random
+    /// one.
+    pub client_id: Option<String>,
+}
+
+impl ConnectionOptions {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            protocol: HubProtocol::Json,
+            transports: vec![TransportType::WebSockets],
+            access_token_factory: None,
+            skip_negotiation: true,
+            token_refresh_attempts: 2,
+            headers: HashMap::new(),
+            #[cfg(not(feature = "native"))]
+            websocket_factory: None,
+            transport_factory: None,
+            runtime: None,
+            reconnect_policy: None,
+            handshake_timeout: Duration::from_secs(15),
+            server_timeout: Duration::from_secs(30),
+            keep_alive_interval: Duration::from_secs(15),
+            log_level: LogLevel::Info,
+            logger: None,
+            channel_capacity: 64,
+            overflow_policy: OverflowPolicy::Block,
+            offline_queue: None,
+            message_headers: None,
+            trace_provider: None,
+            invocation_retries: 0,
+            client_id: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.url.trim().is_empty() {
+            return Err("A hub URL is required".to_owned());
+        }
+
+        // Relative URLs are resolved against the page when connecting, and there's no page
+        // outside a browser.
+        if HubUrl::is_absolute(&self.url) {
+            HubUrl::parse(&self.url, None)?;
+        } else if cfg!(not(target_arch = "wasm32")) {
+            return Err(format!("Hub URL `{}` must be absolute", self.url));
+        }
+
+        if !self.transports.contains(&TransportType::WebSockets) {
+            return Err(format!(
+                "None of the requested transports are supported: {:?}",
+                self.transports
+            ));
+        }
+
+        if self.runtime.is_none() && runtime::default_runtime().is_none() {
+            return Err(
+                "No runtime is configured, and neither the wasm-runtime nor the tokio-runtime \
+                 feature is enabled"
+                    .to_owned(),
+            );
+        }
+
+        if cfg!(feature = "native") && !self.skip_negotiation {
+            return Err("The native backend does not support negotiation".to_owned());
+        }
+
+        if self.handshake_timeout.is_zero() {
+            return Err("Handshake timeout must be greater than zero".to_owned());
+        }
+
+        if self.keep_alive_interval >= self.server_timeout {
+            return Err(format!(
+                "Keep-alive interval ({:?}) must be less than the server timeout ({:?})",
+                self.keep_alive_interval, self.server_timeout
+            ));
+        }
+
+        if self.channel_capacity == 0 {
+            return Err("Channel capacity must be greater than zero".to_owned());
+        }
+
+        if self.client_id.as_deref() == Some("") {
+            return Err("Client ID must not be empty".to_owned());
+        }
+
+        if let Some(queue) = &self.offline_queue {
+            if queue.max_size == 0 || queue.expiry.is_zero() {
+                return Err("Offline queue size and expiry must be greater than zero".to_owned());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct HubConnectionBuilder {
+    options: ConnectionOptions,
+}
+
+impl Default for HubConnectionBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HubConnectionBuilder {
+    pub fn new() -> Self {
+        Self {
+            options: ConnectionOptions::new(""),
+        }
+    }
+
+    pub fn with_url(mut self, url: &str) -> Self {
+        self.options.url = url.to_owned();
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: HubProtocol) -> Self {
+        self.options.protocol = protocol;
+        self
+    }
+
+    pub fn with_transports(mut self, transports: &[TransportType]) -> Self {
+        self.options.transports = transports.to_vec();
+        self
+    }
+
+    pub fn with_access_token_factory<F, Fut>(mut self, factory: F) -> Self
+    where
+        F: Fn(bool) -> Fut + 'static,
+        Fut: Future<Output = Result<String, String>> + 'static,
+    {
+        self.options.access_token_factory = Some(Rc::new(move |force_refresh| {
+            factory(force_refresh).boxed_local()
+        }));
+        self
+    }
+
+    pub fn with_token_refresh_attempts(mut self, attempts: u32) -> Self {
+        self.options.token_refresh_attempts = attempts;
+        self
+    }
+
+    /// Negotiation is skipped by default; pass `false` to negotiate first, which is also what
+    /// lets a 401 from the negotiate endpoint trigger a token refresh.
+    pub fn with_skip_negotiation(mut self, skip_negotiation: bool) -> Self {
+        self.options.skip_negotiation = skip_negotiation;
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.options
+            .headers
+            .insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Creates WebSockets with `constructor`, such as the `ws` package's in Node, instead of
+    /// the global `WebSocket`. Called as `new constructor(url, [], { headers })`.
+    #[cfg(not(feature = "native"))]
+    pub fn with_websocket_constructor(mut self, constructor: js_sys::Function) -> Self {
+        self.options.websocket_factory = Some(constructor_factory(constructor));
+        self
+    }
+
+    /// Creates WebSockets with `factory` instead of the global `WebSocket`. It's given the URL
+    /// and configured headers, and may return any object with a `WebSocket`'s interface.
+    #[cfg(not(feature = "native"))]
+    pub fn with_websocket_factory<F>(mut self, factory: F) -> Self
+    where
+        F: Fn(&str, &HashMap<String, String>) -> Result<web_sys::WebSocket, String> + 'static,
+    {
+        self.options.websocket_factory = Some(Rc::new(factory));
+        self
+    }
+
+    /// Connects through transports created by `factory` instead of WebSockets.
+    pub fn with_transport<F, T>(mut self, factory: F) -> Self
+    where
+        F: Fn() -> T + 'static,
+        T: Transport + 'static,
+    {
+        self.options.transport_factory = Some(Rc::new(move || Box::new(factory())));
+        self
+    }
+
+    pub fn with_runtime(mut self, runtime: impl Runtime + 'static) -> Self {
+        self.options.runtime = Some(Rc::new(runtime));
+        self
+    }
+
+    /// Reconnects using [`DefaultReconnectPolicy`].
+    pub fn with_automatic_reconnect(self) -> Self {
+        self.with_reconnect_policy(DefaultReconnectPolicy::default())
+    }
+
+    pub fn with_reconnect_policy(mut self, policy: impl ReconnectPolicy + 'static) -> Self {
+        self.options.reconnect_policy = Some(Rc::new(policy));
+        self
+    }
+
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.options.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn with_server_timeout(mut self, timeout: Duration) -> Self {
+        self.options.server_timeout = timeout;
+        self
+    }
+
+    pub fn with_keep_alive_interval(mut self, interval: Duration) -> Self {
+        self.options.keep_alive_interval = interval;
+        self
+    }
+
+    pub fn with_log_level(mut self, level: LogLevel) -> Self {
+        self.options.log_level = level;
+        self
+    }
+
+    /// Sends log messages to `logger`, which may be a `Fn(LogLevel, &str)`.
+    pub fn with_logger(mut self, logger: impl Logger + 'static) -> Self {
+        self.options.logger = Some(Rc::new(logger));
+        self
+    }
+
+    pub fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        self.options.channel_capacity = capacity;
+        self
+    }
+
+    pub fn with_overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.options.overflow_policy = policy;
+        self
+    }
+
+    /// Queues up to `max_size` invocations made while connecting or reconnecting, each for at
+    /// most `expiry`, and sends them in order once connected.
+    pub fn with_offline_queue(mut self, max_size: usize, expiry: Duration) -> Self {
+        self.options.offline_queue = Some(OfflineQueue { max_size, expiry });
+        self
+    }
+
+    /// Adds the headers `factory` returns to every invocation and completion sent, for
+    /// correlation ids or routing metadata. It's called once per message.
+    pub fn with_message_headers<F>(mut self, factory: F) -> Self
+    where
+        F: Fn() -> HashMap<String, String> + 'static,
+    {
+        self.options.message_headers = Some(Rc::new(factory));
+        self
+    }
+
+    pub fn with_trace_provider(mut self, provider: impl TraceProvider + 'static) -> Self {
+        self.options.trace_provider = Some(Rc::new(provider));
+        self
+    }
+
+    /// Sends idempotent invocations up to `retries` more times, after reconnecting, when the
+    /// connection is lost before their result arrives.
+    pub fn with_invocation_retries(mut self, retries: u32) -> Self {
+        self.options.invocation_retries = retries;
+        self
+    }
+
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.options.client_id = Some(client_id.to_owned());
+        self
+    }
+
+    pub fn build(self) -> Result<SignalRConnection, String> {
+        self.options.validate()?;
+
+        Ok(SignalRConnection::from_validated(self.options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SignalRError;
+
+    /// Validation only checks that there is a runtime.
+    struct UnusedRuntime;
+
+    impl Runtime for UnusedRuntime {
+        fn spawn_local(&self, _: LocalBoxFuture<'static, ()>) {
+            unreachable!()
+        }
+
+        fn sleep(&self, _: Duration) -> LocalBoxFuture<'static, ()> {
+            unreachable!()
+        }
+
+        fn now(&self) -> Duration {
+            unreachable!()
+        }
+    }
+
+    fn options() -> ConnectionOptions {
+        ConnectionOptions {
+            runtime: Some(Rc::new(UnusedRuntime)),
+            ..ConnectionOptions::new("https://example.com/hub")
+        }
+    }
+
+    #[test]
+    fn valid_options_pass() {
+        assert_eq!(options().validate(), Ok(()));
+    }
+
+    #[test]
+    fn channel_capacity_must_not_be_zero() {
+        let options = ConnectionOptions {
+            channel_capacity: 0,
+            ..options()
+        };
+
+        assert_eq!(
+            options.validate(),
+            Err("Channel capacity must be greater than zero".to_owned())
+        );
+    }
+
+    #[test]
+    fn connections_are_not_created_from_invalid_options() {
+        let options = ConnectionOptions {
+            channel_capacity: 0,
+            ..options()
+        };
+
+        assert!(matches!(
+            SignalRConnection::with_options(options),
+            Err(SignalRError::Other(message)) if message == "Channel capacity must be greater than zero"
+        ));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn relative_urls_are_rejected_outside_a_browser() {
+        let options = ConnectionOptions {
+            url: "/hub".to_owned(),
+            ..options()
+        };
+
+        assert_eq!(
+            options.validate(),
+            Err("Hub URL `/hub` must be absolute".to_owned())
+        );
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn negotiation_is_rejected_natively() {
+        let options = ConnectionOptions {
+            skip_negotiation: false,
+            ..options()
+        };
+
+        assert_eq!(
+            options.validate(),
+            Err("The native backend does not support negotiation".to_owned())
+        );
+    }
+
+    #[cfg(not(any(feature = "wasm-runtime", feature = "tokio-runtime")))]
+    #[test]
+    fn a_runtime_is_required_without_a_runtime_feature() {
+        let options = ConnectionOptions {
+            runtime: None,
+            ..options()
+        };
+
+        assert!(options.validate().unwrap_err().contains("No runtime"));
+    }
+}
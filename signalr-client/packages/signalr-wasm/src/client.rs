@@ -1,6 +1,8 @@
+use futures::StreamExt;
 use js_sys::Function;
 use serde_json::Value;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
 
 use crate::connection::SignalRConnection;
 
@@ -13,17 +15,38 @@ pub struct ChatClient {
 #[wasm_bindgen]
 impl ChatClient {
     #[wasm_bindgen(constructor)]
-    pub fn new(url: &str, user: String) -> Self {
-        let connection = SignalRConnection::new(url);
+    pub fn new(url: &str, user: String) -> Result<ChatClient, JsValue> {
+        let connection = SignalRConnection::new(url)?;
 
-        Self { connection, user }
+        Ok(Self { connection, user })
     }
 
-    pub async fn connect(&mut self) -> Result<(), JsValue> {
+    pub async fn connect(&self) -> Result<(), JsValue> {
         self.connection.connect().await.map_err(JsValue::from)
     }
 
-    pub fn on_message_received(&mut self, callback: Function) -> () {
+    pub async fn disconnect(&self) -> Result<(), JsValue> {
+        self.connection.stop(true).await.map_err(JsValue::from)
+    }
+
+    pub fn on_state_changed(&self, callback: Function) {
+        let mut changes = self.connection.state_changes();
+        let log = self.connection.log().clone();
+
+        spawn_local(async move {
+            while let Some(change) = changes.next().await {
+                let state = JsValue::from(change.current.as_str());
+
+                if let Err(e) = callback.call1(&JsValue::null(), &state) {
+                    log_error!(log, "Failed to invoke on_state_changed: {:?}", e);
+                }
+            }
+        });
+    }
+
+    pub fn on_message_received(&self, callback: Function) {
+        let log = self.connection.log().clone();
+
         self.connection
             .on("ReceiveMessage", move |user: String, message: String| {
                 let this = JsValue::null();
@@ -31,7 +54,7 @@ impl ChatClient {
                 let message_val = JsValue::from(message);
 
                 if let Err(e) = callback.call2(&this, &user_val, &message_val) {
-                    console_error!("Failed to invoke on_message_received: {:?}", e);
+                    log_error!(log, "Failed to invoke on_message_received: {:?}", e);
                 }
             });
     }
@@ -40,7 +63,7 @@ impl ChatClient {
         self.user = user;
     }
 
-    pub async fn send_message(&mut self, message: &str) -> Result<(), JsValue> {
+    pub async fn send_message(&self, message: &str) -> Result<(), JsValue> {
         self.connection
             .send_invocation(
                 "SendMessage".to_owned(),
@@ -50,6 +73,7 @@ impl ChatClient {
                 ],
             )
             .await
+            .map(|_| ())
             .map_err(JsValue::from)
     }
 }
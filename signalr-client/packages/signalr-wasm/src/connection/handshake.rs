@@ -1,115 +1,252 @@
-use crate::connection::SignalRConnection;
+use crate::connection::reader::{feed, next_wakeup};
+use crate::connection::{transmit, SignalRConnection};
+use crate::error::SignalRError;
+use crate::protocol::{HubProtocolState, ProtocolEvent};
+use crate::state::ConnectionState;
+use crate::transport::{Transport, TransportEvent, WebSocketTransport, NORMAL_CLOSURE};
+use crate::url::HubUrl;
 use futures::channel::oneshot;
-use serde::{Deserialize, Serialize};
-use wasm_bindgen::closure::Closure;
-use wasm_bindgen::JsCast;
-use web_sys::{MessageEvent, WebSocket};
-
-#[derive(Serialize)]
-struct HandshakeRequest {
-    protocol: &'static str,
-    version: u8,
-}
-
-#[derive(Deserialize)]
-struct HandshakeResponse {
-    error: Option<String>,
-}
+use futures::future::{select, Either};
+use futures::stream::LocalBoxStream;
+use futures::FutureExt;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+/// The open transport, the protocol state after the handshake and the transport's events.
+type HandshakeResult = Result<
+    (
+        Box<dyn Transport>,
+        HubProtocolState,
+        LocalBoxStream<'static, TransportEvent>,
+    ),
+    SignalRError,
+>;
 
 impl SignalRConnection {
-    pub async fn connect(&mut self) -> Result<(), String> {
-        let ws = match WebSocket::new(self.url.as_str()) {
-            Ok(ws) => ws,
-            Err(_) => {
-                return Err(String::from("Failed to create websocket"));
-            }
+    /// Fails with [`SignalRError::Aborted`] if [`stop`](Self::stop) is called before the
+    /// connection is open.
+    pub async fn connect(&self) -> Result<(), SignalRError> {
+        self.inner.state.transition(
+            "start",
+            &[ConnectionState::Disconnected],
+            ConnectionState::Connecting,
+        )?;
+
+        let mut guard = AbandonOnDrop {
+            connection: self,
+            error: Some(SignalRError::Aborted),
         };
 
-        let (open_sender, open_receiver) = oneshot::channel::<()>();
-        let (handshake_sender, handshake_receiver) = oneshot::channel::<Result<(), String>>();
-
-        let on_open = Closure::once(move || {
-            if let Err(e) = open_sender.send(()) {
-                console_error!("Failed to send open message: {:?}", e);
-            }
-        });
+        let (cancel, cancelled) = oneshot::channel();
+        *self.inner.cancel_connect.borrow_mut() = Some(cancel);
 
-        let on_message = Closure::once(move |e: MessageEvent| {
-            let parse_result = Self::parse_message(&e);
+        // The cancellation is polled first, so a handshake completing after `stop` isn't used.
+        let opened = select(cancelled, self.open(false).boxed_local()).await;
+        self.inner.cancel_connect.borrow_mut().take();
 
-            /* TODO: improve error handling here - can we avoid expect / panics? */
+        match opened {
+            // `stop` already disconnected, and dropping `open` closes the transport.
+            Either::Left(_) => {
+                guard.error = None;
+                return Err(SignalRError::Aborted);
+            }
+            Either::Right((Err(e), _)) => {
+                guard.error = Some(e.clone());
+                return Err(e);
+            }
+            Either::Right((Ok(()), _)) => {}
+        }
 
-            let message: String = match parse_result {
-                Ok(mut msg) => msg.remove(0),
-                Err(e) => {
-                    handshake_sender
-                        .send(Err(e))
-                        .expect("Failed to send on_message error");
-                    return;
-                }
-            };
+        guard.error = None;
+        self.inner.state.transition(
+            "complete the connection",
+            &[ConnectionState::Connecting],
+            ConnectionState::Connected,
+        )?;
+        self.inner.flush_outbox();
 
-            console_log!("Received handshake response: {}", message);
+        Ok(())
+    }
 
-            let parsed_result: HandshakeResponse = match serde_json::from_str(&message) {
-                Ok(val) => val,
-                Err(e) => {
-                    handshake_sender
-                        .send(Err(format!("Failed to parse JSON: {}", e)))
-                        .expect("Failed to send on_message error");
-                    return;
-                }
+    /// Opens the connection, asking the access token factory for a fresh token and trying
+    /// again if the server rejects the current one. Gives up with
+    /// [`SignalRError::Unauthorized`] once the configured number of refreshes is used up.
+    pub(super) async fn open(&self, force_refresh: bool) -> Result<(), SignalRError> {
+        let mut force_refresh = force_refresh;
+        let mut refreshes = 0;
+
+        loop {
+            let error = match self.open_once(force_refresh).await {
+                Err(e) if e.is_auth_failure() => e,
+                result => return result,
             };
 
-            if let Some(error) = parsed_result.error {
-                handshake_sender
-                    .send(Err(format!("Received handshake error: {}", error)))
-                    .expect("Failed to send on_message error");
-                return;
+            if self.inner.options.access_token_factory.is_none()
+                || refreshes >= self.inner.options.token_refresh_attempts
+            {
+                return Err(match error {
+                    SignalRError::Unauthorized(_) => error,
+                    other => SignalRError::Unauthorized(other.to_string()),
+                });
             }
 
-            handshake_sender
-                .send(Ok(()))
-                .expect("Failed to send on_message success");
-        });
+            log_info!(
+                self.inner.log,
+                "Authentication failed, refreshing access token: {}",
+                error
+            );
+            refreshes += 1;
+            force_refresh = true;
+        }
+    }
+
+    /// Fetches an access token, negotiates if enabled, then opens a transport and performs
+    /// the handshake before starting to read from it.
+    async fn open_once(&self, force_refresh: bool) -> Result<(), SignalRError> {
+        let mut access_token = match &self.inner.options.access_token_factory {
+            Some(factory) => Some(
+                factory(force_refresh)
+                    .await
+                    .map_err(|e| format!("Failed to get access token: {}", e))?,
+            ),
+            None => None,
+        };
 
-        ws.set_onopen(Some(on_open.as_ref().unchecked_ref()));
-        ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        let mut url = HubUrl::resolve(&self.inner.options.url)?;
+        if !self.inner.options.skip_negotiation {
+            let negotiated = self.negotiate(url, access_token).await?;
+            url = negotiated.url;
+            access_token = negotiated.access_token;
+        }
 
-        match open_receiver.await {
-            Ok(()) => {
-                console_log!("Received open event, transmitting handshake...");
+        let mut headers = self.inner.options.headers.clone();
+        if let Some(token) = access_token.filter(|t| !t.is_empty()) {
+            // Browsers can't set headers on WebSocket upgrades, so the token goes in the URL
+            // there too. Natively the header is enough, keeping it out of server logs.
+            if cfg!(not(feature = "native")) {
+                url.append_query("access_token", &token);
             }
-            Err(e) => return Err(format!("Failed to get open event: {}", e)),
+            headers.insert("Authorization".to_owned(), format!("Bearer {}", token));
         }
 
-        let request = HandshakeRequest {
-            protocol: "json",
-            version: 1,
+        let (transport, protocol, events) = self.handshake(url.ws_url(), headers).await?;
+        let protocol = Rc::new(RefCell::new(protocol));
+
+        *self.inner.transport.borrow_mut() = Some(transport);
+        *self.inner.protocol.borrow_mut() = Some(protocol.clone());
+
+        self.spawn_handlers(&protocol);
+        self.start_reader(protocol, events);
+
+        Ok(())
+    }
+
+    /// Opens a transport and completes the handshake over it, failing early if the
+    /// transport closes first. The handshake timeout covers opening the transport too. The
+    /// transport is closed if this fails or is dropped before finishing.
+    async fn handshake(&self, url: String, headers: HashMap<String, String>) -> HandshakeResult {
+        let mut protocol = HubProtocolState::new(&self.inner.options);
+        protocol.start(self.inner.runtime.now())?;
+
+        let mut transport = CloseOnDrop(Some(match &self.inner.options.transport_factory {
+            Some(factory) => factory(),
+            None => Box::new(WebSocketTransport::from_options(&self.inner.options)),
+        }));
+
+        let deadline = self.inner.runtime.sleep_until(protocol.poll_timeout());
+        let mut events = match select(transport.connect(&url, &headers), deadline).await {
+            Either::Left((result, _)) => result?,
+            Either::Right(_) => {
+                return Err(SignalRError::HandshakeTimeout(
+                    self.inner.options.handshake_timeout,
+                ))
+            }
         };
-        if let Err(e) = Self::send_struct(&ws, &request) {
-            return Err(format!("Failed to send handshake: {:?}", e));
-        }
 
-        match handshake_receiver.await {
-            Ok(result) => {
-                if let Err(e) = result {
-                    return Err(format!("Handshake failed: {}", e));
+        log_debug!(self.inner.log, "Transport open, transmitting handshake");
+        transmit(&mut protocol, transport.as_ref())
+            .map_err(|e| format!("Failed to send handshake: {:?}", e))?;
+
+        loop {
+            match protocol.poll_event() {
+                Some(ProtocolEvent::HandshakeCompleted) => break,
+                Some(ProtocolEvent::HandshakeFailed(e)) => return Err(e),
+                Some(event) => log_warn!(
+                    self.inner.log,
+                    "Unexpected event during the handshake: {:?}",
+                    event
+                ),
+                None => {
+                    let runtime = self.inner.runtime.as_ref();
+                    let wakeup = next_wakeup(runtime, &mut events, protocol.poll_timeout()).await;
+
+                    if !feed(&mut protocol, wakeup, runtime.now()) {
+                        return Err("Transport closed during the handshake".to_owned().into());
+                    }
                 }
-
-                console_log!("Successfully established connection");
             }
-            Err(e) => return Err(format!("Failed to get handshake event: {}", e)),
         }
 
-        ws.set_onopen(None);
-        ws.set_onmessage(None);
+        log_info!(self.inner.log, "Successfully established connection");
+
+        Ok((transport.into_inner(), protocol, events))
+    }
+}
+
+/// Closes a transport that's being given up on before the handshake completes.
+struct CloseOnDrop(Option<Box<dyn Transport>>);
 
-        // TODO: handle gracefully
-        self.web_socket.set(ws).unwrap();
+impl CloseOnDrop {
+    fn into_inner(mut self) -> Box<dyn Transport> {
+        self.0.take().expect("transport already taken")
+    }
+}
 
-        self.start_reader()?;
+impl Deref for CloseOnDrop {
+    type Target = Box<dyn Transport>;
 
-        Ok(())
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("transport already taken")
+    }
+}
+
+impl DerefMut for CloseOnDrop {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut().expect("transport already taken")
+    }
+}
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        if let Some(transport) = self.0.take() {
+            transport.close(NORMAL_CLOSURE);
+        }
+    }
+}
+
+/// Puts the connection back into the disconnected state if `connect` fails or is dropped
+/// before it finishes, reporting `error` to state watchers.
+struct AbandonOnDrop<'a> {
+    connection: &'a SignalRConnection,
+    error: Option<SignalRError>,
+}
+
+impl Drop for AbandonOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Some(error) = self.error.take() {
+            let reason = Some(error.to_string());
+            self.connection
+                .inner
+                .fail_outbox(SignalRError::ConnectionClosed { reason });
+
+            let _ = self.connection.inner.state.transition_with_error(
+                "abandon the connection",
+                &[ConnectionState::Connecting],
+                ConnectionState::Disconnected,
+                Some(error),
+            );
+        }
     }
 }
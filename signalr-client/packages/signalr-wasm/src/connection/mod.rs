@@ -1,119 +1,326 @@
 mod handshake;
+mod negotiate;
+mod outbox;
 mod reader;
 mod receive_invocation;
+mod reconnect;
 mod send_invocation;
+mod stop;
+mod subscription;
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests;
 
-use futures::channel::mpsc::{self, Receiver, Sender};
-use futures::SinkExt;
-use serde::Serialize;
-use std::cell::{OnceCell, RefCell};
+use futures::channel::oneshot;
+use futures::future::LocalBoxFuture;
+use futures::Stream;
+use serde_json::Value;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use std::rc::Rc;
-use wasm_bindgen_futures::spawn_local;
-use web_sys::MessageEvent;
-
-use wasm_bindgen::prelude::*;
+use std::rc::{Rc, Weak};
 
+use crate::builder::{ConnectionOptions, HubConnectionBuilder, SubscriptionOptions};
+use crate::error::SignalRError;
+use crate::log::Log;
 use crate::message::{CompletionMessage, InvocationMessage};
-use wasm_bindgen::closure::Closure;
-use web_sys::WebSocket;
+use crate::protocol::HubProtocolState;
+use crate::runtime::{self, Runtime};
+use crate::state::{ConnectionState, StateChange, StateMachine};
+use crate::trace::ActiveSpan;
+use crate::transport::Transport;
+use crate::utils;
+use outbox::Outbox;
+use subscription::Sender;
 
-const CHANNEL_BOUND_SIZE: usize = 64;
+pub use receive_invocation::{HandlerExecution, HandlerResult};
+pub use send_invocation::{Invocation, InvocationOptions, IDEMPOTENCY_KEY_HEADER};
 
-type CompletionSubscriberMap = HashMap<String, Sender<CompletionMessage>>;
+type CompletionSubscriberMap = HashMap<String, PendingInvocation>;
 type InvocationSubscriberMap = HashMap<String, Sender<InvocationMessage>>;
+type InvocationStreamMap = HashMap<String, Vec<StreamSubscription>>;
+type HandlerFn = dyn Fn(InvocationMessage) -> LocalBoxFuture<'static, Result<Value, String>>;
+
+/// An invocation as the caller made it, kept to send it later or again.
+#[derive(Clone)]
+struct InvocationRequest {
+    target: String,
+    arguments: Vec<Value>,
+    headers: HashMap<String, String>,
+    /// Only set for idempotent invocations, which are sent again after reconnecting.
+    idempotency_key: Option<String>,
+    /// How many times it was sent again.
+    retries: u32,
+}
+
+/// Where an invocation's completion goes. Ends the invocation's span, if it has one, when
+/// the completion or error is sent.
+struct ResultSender {
+    sender: oneshot::Sender<Result<CompletionMessage, SignalRError>>,
+    span: Option<ActiveSpan>,
+}
+
+impl ResultSender {
+    /// Fails if the invocation's handle was dropped.
+    fn send(self, result: Result<CompletionMessage, SignalRError>) -> Result<(), ()> {
+        if let Some(span) = self.span {
+            let error = match &result {
+                Ok(message) => message.error.clone(),
+                Err(error) => Some(error.to_string()),
+            };
+            span.end(error);
+        }
+
+        self.sender.send(result).map_err(|_| ())
+    }
+}
+
+/// A sent invocation waiting for its completion.
+struct PendingInvocation {
+    result: ResultSender,
+    /// What to send again if the connection is lost first, for idempotent invocations.
+    retry: Option<InvocationRequest>,
+}
+
+/// A stream from [`SignalRConnection::on_stream`]: where its invocations are queued, and
+/// where the error it ends with is sent.
+struct StreamSubscription {
+    sender: Sender<InvocationMessage>,
+    closed: oneshot::Sender<SignalRError>,
+}
+
+#[derive(Clone)]
+struct InvocationHandler {
+    call: Rc<HandlerFn>,
+    execution: HandlerExecution,
+}
 
 pub struct SignalRConnection {
-    url: String,
-    web_socket: OnceCell<WebSocket>,
-    on_message_closure: Option<Closure<dyn FnMut(MessageEvent)>>,
-    invocation_id: u64,
+    inner: Rc<ConnectionInner>,
+}
+
+/// State shared between the connection and the tasks it spawns. Tasks only hold a
+/// [`Weak`] reference, so dropping the [`SignalRConnection`] tears everything down.
+struct ConnectionInner {
+    options: ConnectionOptions,
+    runtime: Rc<dyn Runtime>,
+    log: Log,
+    state: StateMachine,
+    /// Fired by [`SignalRConnection::stop`] to abort a `connect` that's still opening.
+    cancel_connect: RefCell<Option<oneshot::Sender<()>>>,
+    transport: RefCell<Option<Box<dyn Transport>>>,
+    /// Also held by the reader task driving it, so each attempt's task only sees its own.
+    protocol: RefCell<Option<Rc<RefCell<HubProtocolState>>>>,
     completion_subscribers: Rc<RefCell<CompletionSubscriberMap>>,
     invocation_subscribers: Rc<RefCell<InvocationSubscriberMap>>,
+    /// Streams opened with [`SignalRConnection::on_stream`]. They're ended when the
+    /// connection closes, reconnects included, where handlers are restarted instead.
+    invocation_streams: Rc<RefCell<InvocationStreamMap>>,
+    handlers: RefCell<HashMap<String, InvocationHandler>>,
+    subscription_options: RefCell<HashMap<String, SubscriptionOptions>>,
+    /// Invocations each target's subscriptions discarded to make room.
+    dropped_messages: RefCell<HashMap<String, Rc<Cell<u64>>>>,
+    outbox: RefCell<Outbox>,
+    client_id: String,
+    /// The sequence number in the last idempotency key handed out.
+    last_idempotency_key: Cell<u64>,
 }
 
 impl SignalRConnection {
-    pub fn new(url: &str) -> Self {
-        Self {
-            url: String::from(url),
-            web_socket: OnceCell::new(),
-            on_message_closure: None,
-            invocation_id: 0,
+    /// Connects to `url` with the default options, like [`HubConnectionBuilder::build`].
+    pub fn new(url: &str) -> Result<Self, SignalRError> {
+        Ok(HubConnectionBuilder::new().with_url(url).build()?)
+    }
+
+    /// Fails if [`ConnectionOptions::validate`] rejects `options`.
+    pub fn with_options(options: ConnectionOptions) -> Result<Self, SignalRError> {
+        options.validate()?;
+
+        Ok(Self::from_validated(options))
+    }
+
+    /// Expects `options` to have passed [`ConnectionOptions::validate`], which checks there's
+    /// a runtime.
+    pub(crate) fn from_validated(options: ConnectionOptions) -> Self {
+        let runtime = options
+            .runtime
+            .clone()
+            .or_else(runtime::default_runtime)
+            .expect("no runtime is configured and no runtime feature is enabled");
+
+        let client_id = options.client_id.clone().unwrap_or_else(utils::random_id);
+        let inner = ConnectionInner {
+            log: Log::from_options(&options),
+            options,
+            runtime,
+            state: StateMachine::new(),
+            cancel_connect: RefCell::new(None),
+            transport: RefCell::new(None),
+            protocol: RefCell::new(None),
             completion_subscribers: Rc::new(RefCell::new(CompletionSubscriberMap::new())),
             invocation_subscribers: Rc::new(RefCell::new(InvocationSubscriberMap::new())),
+            invocation_streams: Rc::new(RefCell::new(InvocationStreamMap::new())),
+            handlers: RefCell::new(HashMap::new()),
+            subscription_options: RefCell::new(HashMap::new()),
+            dropped_messages: RefCell::new(HashMap::new()),
+            outbox: RefCell::new(Outbox::default()),
+            client_id,
+            last_idempotency_key: Cell::new(0),
+        };
+
+        Self {
+            inner: Rc::new(inner),
         }
     }
 
-    fn parse_message(e: &MessageEvent) -> Result<Vec<String>, String> {
-        let data: String;
+    #[cfg(not(feature = "native"))]
+    pub(crate) fn log(&self) -> &Log {
+        &self.inner.log
+    }
 
-        if let Ok(text) = e.data().dyn_into::<js_sys::JsString>() {
-            data = text.into();
-        } else {
-            return Err("Unsupported wire format".to_owned());
-        }
+    fn downgrade(&self) -> Weak<ConnectionInner> {
+        Rc::downgrade(&self.inner)
+    }
+
+    /// Recovers a handle from a task's [`Weak`] reference, if the connection is still alive.
+    fn upgrade(inner: &Weak<ConnectionInner>) -> Option<Self> {
+        inner.upgrade().map(|inner| Self { inner })
+    }
+
+    /// Identifies this connection in the idempotency keys of its invocations, across
+    /// reconnects.
+    pub fn client_id(&self) -> &str {
+        &self.inner.client_id
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.inner.state.get()
+    }
 
-        Ok(data.split_terminator('\x1E').map(str::to_owned).collect())
+    /// Yields every state change from now on, until the connection is dropped.
+    pub fn state_changes(&self) -> impl Stream<Item = StateChange> {
+        self.inner.state.watch()
     }
 
-    fn open_message_channel(&mut self) -> Result<Receiver<String>, String> {
-        if self.on_message_closure.is_some() {
-            return Err("Already listening for messages".to_owned());
+    /// Queues invocations of `method_name` according to `options` instead of the connection's
+    /// defaults. Applies to streams opened afterwards, and to its handler from the next
+    /// `connect`, or right away if it's registered afterwards.
+    pub fn set_subscription_options(
+        &self,
+        method_name: &str,
+        options: SubscriptionOptions,
+    ) -> Result<(), String> {
+        if options.capacity == 0 {
+            return Err("Channel capacity must be greater than zero".to_owned());
         }
 
-        let ws: &WebSocket = match self.web_socket.get_mut() {
-            Some(ws) => ws,
-            None => {
-                return Err("No open socket".to_owned());
-            }
-        };
+        self.inner
+            .subscription_options
+            .borrow_mut()
+            .insert(method_name.to_owned(), options);
+
+        Ok(())
+    }
+
+    /// How many invocations of each target were discarded because their subscribers fell
+    /// behind. Targets that never dropped anything are left out.
+    pub fn dropped_messages(&self) -> HashMap<String, u64> {
+        self.inner
+            .dropped_messages
+            .borrow()
+            .iter()
+            .map(|(target, count)| (target.clone(), count.get()))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+}
 
-        let (sender, receiver) = mpsc::channel::<String>(CHANNEL_BOUND_SIZE);
-
-        let on_message_closure = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
-            let mut sender_clone = sender.clone();
-
-            spawn_local(async move {
-                let parsed = match Self::parse_message(&e) {
-                    Ok(vec) => vec,
-                    Err(e) => {
-                        console_error!("Failed to parse message: {}", e);
-                        return;
-                    }
-                };
-
-                for message in parsed {
-                    if let Err(e) = sender_clone.send(message).await {
-                        console_error!("Failed to send message: {}", e);
-                        return;
-                    }
-                }
+impl ConnectionInner {
+    /// Creates the queue for a new handler or stream of `method_name`.
+    fn subscribe(
+        &self,
+        method_name: &str,
+    ) -> (
+        Sender<InvocationMessage>,
+        subscription::Receiver<InvocationMessage>,
+    ) {
+        let options = self
+            .subscription_options
+            .borrow()
+            .get(method_name)
+            .copied()
+            .unwrap_or(SubscriptionOptions {
+                capacity: self.options.channel_capacity,
+                overflow_policy: self.options.overflow_policy,
             });
-        });
+        let dropped = self
+            .dropped_messages
+            .borrow_mut()
+            .entry(method_name.to_owned())
+            .or_default()
+            .clone();
+
+        subscription::channel(options.capacity, options.overflow_policy, dropped)
+    }
+
+    /// Sends every frame the current protocol state has queued.
+    fn flush(&self) -> Result<(), String> {
+        let protocol = self.protocol.borrow();
+        let transport = self.transport.borrow();
+
+        match (protocol.as_ref(), transport.as_ref()) {
+            (Some(protocol), Some(transport)) => {
+                transmit(&mut protocol.borrow_mut(), transport.as_ref())
+            }
+            _ => Err("Not connected".to_owned()),
+        }
+    }
+
+    /// Sends the client's result for an invocation the server sent with an id over
+    /// `protocol`, the attempt it arrived on. Ids are only unique within an attempt, so the
+    /// result is dropped if that attempt has ended.
+    fn complete(
+        &self,
+        protocol: &Rc<RefCell<HubProtocolState>>,
+        invocation_id: String,
+        result: Result<Value, String>,
+    ) -> Result<(), String> {
+        let current = matches!(&*self.protocol.borrow(), Some(p) if Rc::ptr_eq(p, protocol));
+        if !current {
+            log_warn!(
+                self.log,
+                "Dropping the result of invocation {}, as its connection has closed",
+                invocation_id
+            );
+            return Ok(());
+        }
 
-        ws.set_onmessage(Some(on_message_closure.as_ref().unchecked_ref()));
-        self.on_message_closure = Some(on_message_closure);
+        let headers = self.message_headers(HashMap::new());
+        protocol.borrow_mut().complete_with_headers(
+            invocation_id,
+            result,
+            headers,
+            self.runtime.now(),
+        )?;
 
-        Ok(receiver)
+        self.flush()
     }
 
-    fn send_struct(ws: &WebSocket, message: &impl Serialize) -> Result<(), String> {
-        let serialized = serde_json::to_string(message)
-            .map(|mut s| {
-                s.push('\x1E');
-                s
-            })
-            .map_err(|e| format!("Failed to serialize: {}", e))?;
+    /// Adds the configured default headers to an outgoing message's `headers`, without
+    /// replacing any.
+    fn message_headers(&self, mut headers: HashMap<String, String>) -> HashMap<String, String> {
+        if let Some(factory) = &self.options.message_headers {
+            for (name, value) in factory() {
+                headers.entry(name).or_insert(value);
+            }
+        }
 
-        ws.send_with_str(serialized.as_str())
-            .map_err(|e| format!("Failed to send message: {:?}", e))
+        headers
     }
 }
 
-impl Drop for SignalRConnection {
-    fn drop(&mut self) {
-        if let Some(ws) = self.web_socket.get_mut() {
-            ws.set_onmessage(None);
-        }
+fn transmit(protocol: &mut HubProtocolState, transport: &dyn Transport) -> Result<(), String> {
+    while let Some(frame) = protocol.poll_transmit() {
+        transport.send_text(&frame)?;
     }
+
+    Ok(())
 }
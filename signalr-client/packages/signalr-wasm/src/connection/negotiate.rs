@@ -0,0 +1,149 @@
+use crate::connection::SignalRConnection;
+use crate::error::SignalRError;
+use crate::url::HubUrl;
+use js_sys::Promise;
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Headers, Request, RequestInit, Response};
+
+/// The official clients give up after this many redirects.
+const MAX_REDIRECTS: usize = 100;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = fetch)]
+    fn fetch_with_request(input: &Request) -> Promise;
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NegotiateResponse {
+    connection_id: Option<String>,
+    connection_token: Option<String>,
+    #[serde(default)]
+    available_transports: Vec<AvailableTransport>,
+    url: Option<String>,
+    access_token: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AvailableTransport {
+    transport: String,
+}
+
+/// Where to open the socket once negotiation has finished.
+pub(super) struct Negotiated {
+    pub url: HubUrl,
+    pub access_token: Option<String>,
+}
+
+impl SignalRConnection {
+    /// Asks the server how to connect, following redirects to other servers. The access
+    /// token is sent as a bearer token, and may be replaced by one from a redirect.
+    pub(super) async fn negotiate(
+        &self,
+        url: HubUrl,
+        access_token: Option<String>,
+    ) -> Result<Negotiated, SignalRError> {
+        let mut url = url;
+        let mut access_token = access_token;
+
+        for _ in 0..MAX_REDIRECTS {
+            let response = self
+                .send_negotiate_request(&url, access_token.as_deref())
+                .await?;
+
+            if let Some(error) = response.error {
+                return Err(format!("Negotiation failed: {}", error).into());
+            }
+
+            if let Some(redirect_url) = response.url {
+                log_debug!(self.inner.log, "Negotiation redirected to {}", redirect_url);
+                url = HubUrl::parse(&redirect_url, None)?;
+                access_token = response.access_token.or(access_token);
+                continue;
+            }
+
+            if !response
+                .available_transports
+                .iter()
+                .any(|t| t.transport == "WebSockets")
+            {
+                return Err("The server does not support WebSockets".to_owned().into());
+            }
+
+            if let Some(id) = response.connection_token.or(response.connection_id) {
+                url.append_query("id", &id);
+            }
+
+            return Ok(Negotiated { url, access_token });
+        }
+
+        Err(format!("Negotiation exceeded {} redirects", MAX_REDIRECTS).into())
+    }
+
+    async fn send_negotiate_request(
+        &self,
+        url: &HubUrl,
+        access_token: Option<&str>,
+    ) -> Result<NegotiateResponse, SignalRError> {
+        if cfg!(feature = "native") {
+            return Err("The native backend does not support negotiation"
+                .to_owned()
+                .into());
+        }
+
+        let headers = Headers::new().map_err(|e| format!("Failed to create headers: {:?}", e))?;
+        for (name, value) in &self.inner.options.headers {
+            headers
+                .set(name, value)
+                .map_err(|e| format!("Invalid header `{}`: {:?}", name, e))?;
+        }
+
+        if let Some(token) = access_token {
+            headers
+                .set("Authorization", &format!("Bearer {}", token))
+                .map_err(|e| format!("Invalid access token: {:?}", e))?;
+        }
+
+        let init = RequestInit::new();
+        init.set_method("POST");
+        init.set_headers(&headers);
+
+        let request = Request::new_with_str_and_init(&url.negotiate_url(), &init)
+            .map_err(|e| format!("Failed to create negotiate request: {:?}", e))?;
+
+        let response: Response = JsFuture::from(fetch_with_request(&request))
+            .await
+            .and_then(JsCast::dyn_into)
+            .map_err(|e| format!("Negotiate request failed: {:?}", e))?;
+
+        if response.status() == 401 {
+            return Err(SignalRError::Unauthorized(
+                "Negotiate request was rejected with status 401".to_owned(),
+            ));
+        }
+
+        if !response.ok() {
+            return Err(
+                format!("Negotiate request failed with status {}", response.status()).into(),
+            );
+        }
+
+        let body = response
+            .text()
+            .map_err(|e| format!("Failed to read negotiate response: {:?}", e))?;
+        let body: String = JsFuture::from(body)
+            .await
+            .map_err(|e| format!("Failed to read negotiate response: {:?}", e))?
+            .as_string()
+            .unwrap_or_default();
+
+        serde_json::from_str(&body)
+            .map_err(|e| format!("Failed to parse negotiate response: {}", e).into())
+    }
+}
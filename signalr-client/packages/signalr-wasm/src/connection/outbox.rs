@@ -0,0 +1,153 @@
+//! Invocations waiting for the connection to be up: those made while connecting or
+//! reconnecting when an [`OfflineQueue`](crate::builder::OfflineQueue) is configured, and
+//! idempotent ones being retried.
+
+use crate::connection::{ConnectionInner, InvocationRequest, ResultSender, SignalRConnection};
+use crate::error::SignalRError;
+use futures::channel::oneshot;
+use futures::future::{select, Either};
+use futures::FutureExt;
+use std::collections::VecDeque;
+
+pub(super) type SentSender = oneshot::Sender<Result<(), SignalRError>>;
+
+/// An invocation waiting to be sent, and where to report how it went.
+pub(super) struct QueuedInvocation {
+    /// Only identifies it within the queue; the protocol assigns the real id once it's sent.
+    id: u64,
+    request: InvocationRequest,
+    /// Already used when retrying.
+    sent: Option<SentSender>,
+    result: ResultSender,
+    /// Dropped along with the invocation when it leaves the queue, which stops its expiry
+    /// timer. Retries don't expire.
+    _dequeued: Option<oneshot::Sender<()>>,
+}
+
+impl QueuedInvocation {
+    fn fail(self, error: SignalRError) {
+        if let Some(sent) = self.sent {
+            let _ = sent.send(Err(error.clone()));
+        }
+        let _ = self.result.send(Err(error));
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Outbox {
+    queue: VecDeque<QueuedInvocation>,
+    next_id: u64,
+}
+
+impl Outbox {
+    /// Puts invocations that were already sent ahead of everything else, keeping their order.
+    /// Neither the size limit nor the expiry applies to them.
+    pub(super) fn requeue(&mut self, invocations: Vec<(InvocationRequest, ResultSender)>) {
+        for (request, result) in invocations.into_iter().rev() {
+            self.next_id += 1;
+            self.queue.push_front(QueuedInvocation {
+                id: self.next_id,
+                request,
+                sent: None,
+                result,
+                _dequeued: None,
+            });
+        }
+    }
+}
+
+impl SignalRConnection {
+    /// Queues `request`, to be sent once connected unless it expires first. If it can't be
+    /// queued, the error is sent to `result` too.
+    pub(super) fn enqueue(
+        &self,
+        request: InvocationRequest,
+        sent: SentSender,
+        result: ResultSender,
+    ) -> Result<(), SignalRError> {
+        let reject = |result: ResultSender, error: SignalRError| {
+            let _ = result.send(Err(error.clone()));
+            Err(error)
+        };
+
+        let options = match self.inner.options.offline_queue {
+            Some(options) => options,
+            None => return reject(result, "The offline queue is disabled".to_owned().into()),
+        };
+
+        // Checked before borrowing the queue, as rejecting ends the span, which calls back
+        // into the trace provider.
+        if self.inner.outbox.borrow().queue.len() >= options.max_size {
+            return reject(result, SignalRError::QueueFull(options.max_size));
+        }
+
+        let (dequeued_sender, dequeued) = oneshot::channel();
+        let id = {
+            let mut outbox = self.inner.outbox.borrow_mut();
+            outbox.next_id += 1;
+            let id = outbox.next_id;
+            outbox.queue.push_back(QueuedInvocation {
+                id,
+                request,
+                sent: Some(sent),
+                result,
+                _dequeued: Some(dequeued_sender),
+            });
+
+            id
+        };
+
+        let weak_inner = self.downgrade();
+        let expired = self.inner.runtime.sleep(options.expiry);
+
+        self.inner.runtime.spawn_local(
+            async move {
+                // Sent or failed before expiring.
+                if let Either::Right(_) = select(expired, dequeued).await {
+                    return;
+                }
+
+                let inner = match weak_inner.upgrade() {
+                    Some(inner) => inner,
+                    None => return,
+                };
+                let expired = {
+                    let mut outbox = inner.outbox.borrow_mut();
+                    let index = outbox.queue.iter().position(|i| i.id == id);
+                    index.and_then(|index| outbox.queue.remove(index))
+                };
+
+                if let Some(invocation) = expired {
+                    invocation.fail(SignalRError::QueueExpired(options.expiry));
+                }
+            }
+            .boxed_local(),
+        );
+
+        Ok(())
+    }
+}
+
+impl ConnectionInner {
+    /// Sends every queued invocation, oldest first. Called right after becoming connected.
+    pub(super) fn flush_outbox(&self) {
+        let queued: Vec<_> = self.outbox.borrow_mut().queue.drain(..).collect();
+
+        for invocation in queued {
+            let result = self.start_invocation(invocation.request, invocation.result);
+
+            if let Some(sent) = invocation.sent {
+                let _ = sent.send(result);
+            }
+        }
+    }
+
+    /// Fails every queued invocation with `error`, for when the connection won't come back.
+    pub(super) fn fail_outbox(&self, error: SignalRError) {
+        let queued: Vec<_> = self.outbox.borrow_mut().queue.drain(..).collect();
+
+        for invocation in queued {
+            invocation.fail(error.clone());
+        }
+    }
+}
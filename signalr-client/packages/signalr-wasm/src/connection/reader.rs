@@ -1,56 +1,212 @@
-use crate::connection::{CompletionSubscriberMap, InvocationSubscriberMap, SignalRConnection};
-use crate::message::{CompletionMessage, InvocationMessage, SignalRMessage};
-use futures::SinkExt;
-use futures::StreamExt;
-use std::cell::RefMut;
-use wasm_bindgen_futures::spawn_local;
+use crate::connection::subscription::SendError;
+use crate::connection::{
+    CompletionSubscriberMap, ConnectionInner, InvocationStreamMap, InvocationSubscriberMap,
+    SignalRConnection,
+};
+use crate::error::{CloseInfo, SignalRError};
+use crate::log::Log;
+use crate::message::{CompletionMessage, InvocationMessage};
+use crate::protocol::{HubProtocolState, ProtocolEvent};
+use crate::runtime::Runtime;
+use crate::transport::{TransportEvent, ABNORMAL_CLOSURE};
+use futures::future::{select, Either};
+use futures::stream::LocalBoxStream;
+use futures::{FutureExt, StreamExt};
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+/// The error sent back for invocations a full queue discarded.
+const DROPPED_INVOCATION: &str = "Client dropped the invocation.";
+
+/// What woke a driver loop up.
+pub(super) enum Wakeup {
+    /// The next transport event, or `None` once there are no more.
+    Event(Option<TransportEvent>),
+    Timeout,
+}
+
+/// Waits for the transport's next event or for `deadline` to pass, whichever is first.
+pub(super) async fn next_wakeup(
+    runtime: &dyn Runtime,
+    events: &mut LocalBoxStream<'static, TransportEvent>,
+    deadline: Option<Duration>,
+) -> Wakeup {
+    match select(events.next(), runtime.sleep_until(deadline)).await {
+        Either::Left((event, _)) => Wakeup::Event(event),
+        Either::Right(_) => Wakeup::Timeout,
+    }
+}
+
+/// Hands a wakeup at `now` to the protocol state. Returns `false` if the transport's events
+/// ended.
+pub(super) fn feed(protocol: &mut HubProtocolState, wakeup: Wakeup, now: Duration) -> bool {
+    match wakeup {
+        Wakeup::Event(Some(TransportEvent::Text(text))) => protocol.handle_text(&text, now),
+        Wakeup::Event(Some(TransportEvent::Binary(_))) => {
+            log_warn!(
+                protocol.log(),
+                "Ignoring binary message, which the JSON protocol doesn't use"
+            );
+        }
+        Wakeup::Event(Some(TransportEvent::Closed(info))) => {
+            log_info!(protocol.log(), "{}", info);
+            protocol.handle_close(info);
+        }
+        Wakeup::Event(None) => return false,
+        Wakeup::Timeout => protocol.handle_timeout(now),
+    }
+
+    true
+}
 
 impl SignalRConnection {
-    pub fn start_reader(&mut self) -> Result<(), String> {
-        console_log!("Starting read loop");
-
-        let mut receiver = self.open_message_channel()?;
-        let cmp_subscribers_clone = self.completion_subscribers.clone();
-        let inv_subscribers_clone = self.invocation_subscribers.clone();
-
-        spawn_local(async move {
-            while let Some(message) = receiver.next().await {
-                console_log!("Received message: {}", message);
-
-                match serde_json::from_str(&message) {
-                    Ok(SignalRMessage::Completion(m)) => {
-                        if let Err(e) =
-                            Self::handle_completion(m, cmp_subscribers_clone.borrow_mut()).await
-                        {
-                            console_error!("{}", e);
+    /// Drives `protocol` from the transport's events and its own deadlines, sending what it
+    /// queues and dispatching its events, until the connection attempt ends.
+    pub(super) fn start_reader(
+        &self,
+        protocol: Rc<RefCell<HubProtocolState>>,
+        mut events: LocalBoxStream<'static, TransportEvent>,
+    ) {
+        log_debug!(self.inner.log, "Starting read loop");
+
+        let weak_inner = self.downgrade();
+        let runtime = self.inner.runtime.clone();
+
+        self.inner.runtime.spawn_local(
+            async move {
+                loop {
+                    let (pending, deadline) = {
+                        let mut protocol = protocol.borrow_mut();
+                        let pending: Vec<_> =
+                            std::iter::from_fn(|| protocol.poll_event()).collect();
+
+                        (pending, protocol.poll_timeout())
+                    };
+
+                    if deadline.is_some() {
+                        match weak_inner.upgrade() {
+                            Some(inner) => {
+                                if let Err(e) = inner.flush() {
+                                    log_error!(inner.log, "{}", e);
+                                }
+                            }
+                            None => return,
                         }
                     }
-                    Ok(SignalRMessage::Invocation(m)) => {
-                        if let Err(e) =
-                            Self::handle_invocation(m, inv_subscribers_clone.borrow_mut()).await
-                        {
-                            console_error!("{}", e);
+
+                    for event in pending {
+                        if !Self::dispatch(&weak_inner, &protocol, event).await {
+                            return;
                         }
                     }
-                    Ok(SignalRMessage::Ping) => {
-                        console_log!("Pong!");
+
+                    // Closed, either by the server or by `stop`.
+                    if deadline.is_none() {
+                        return;
                     }
-                    Err(e) => {
-                        console_error!("Failed to deserialize message: {}", e);
+
+                    let wakeup = next_wakeup(runtime.as_ref(), &mut events, deadline).await;
+                    let mut protocol = protocol.borrow_mut();
+
+                    if !feed(&mut protocol, wakeup, runtime.now()) {
+                        // Transports only end without a close event when closed locally, in
+                        // which case the protocol is already closed and this does nothing.
+                        protocol.handle_close(CloseInfo {
+                            code: ABNORMAL_CLOSURE,
+                            reason: "The transport ended".to_owned(),
+                            was_clean: false,
+                        });
+                    }
+                }
+            }
+            .boxed_local(),
+        );
+    }
+
+    /// Handles an event of the attempt driven by `protocol`. Returns `false` once the attempt
+    /// has ended, as its remaining events are stale: their ids may already be reused by the
+    /// next attempt.
+    async fn dispatch(
+        weak_inner: &Weak<ConnectionInner>,
+        protocol: &Rc<RefCell<HubProtocolState>>,
+        event: ProtocolEvent,
+    ) -> bool {
+        let (cmp_subscribers, inv_subscribers, inv_streams, log) = match weak_inner.upgrade() {
+            Some(inner) => (
+                inner.completion_subscribers.clone(),
+                inner.invocation_subscribers.clone(),
+                inner.invocation_streams.clone(),
+                inner.log.clone(),
+            ),
+            None => return false,
+        };
+
+        match event {
+            ProtocolEvent::Completion(m) => {
+                if let Err(e) = Self::handle_completion(m, &cmp_subscribers) {
+                    log_warn!(log, "{}", e);
+                }
+            }
+            ProtocolEvent::Invocation(m) => {
+                // The server waits for a completion of invocations with an id, so those that
+                // won't reach a handler are answered with an error.
+                let answer = |id: String, error: &str| {
+                    if let Some(inner) = weak_inner.upgrade() {
+                        if let Err(e) = inner.complete(protocol, id, Err(error.to_owned())) {
+                            log_error!(log, "Failed to answer invocation: {}", e);
+                        }
                     }
                 };
+                let invocation_id = m.invocation_id.clone();
+                let has_handler = inv_subscribers.borrow().contains_key(&m.target);
+
+                let result = Self::handle_invocation(m, &inv_subscribers, &inv_streams, &log).await;
+
+                match result {
+                    Ok(Some(dropped_id)) => answer(dropped_id, DROPPED_INVOCATION),
+                    Ok(None) => {}
+                    Err(error @ SignalRError::Overflow { .. }) => {
+                        log_error!(log, "{}", error);
+
+                        if let Some(id) = invocation_id {
+                            answer(id, DROPPED_INVOCATION);
+                        }
+                        if let Some(connection) = Self::upgrade(weak_inner) {
+                            connection.connection_lost(error, false);
+                        }
+                        return false;
+                    }
+                    Err(e) => log_error!(log, "{}", e),
+                }
+
+                if let (Some(id), false) = (invocation_id, has_handler) {
+                    answer(id, "Client didn't provide a result.");
+                }
             }
-        });
+            ProtocolEvent::Closed {
+                error,
+                allow_reconnect,
+            } => {
+                if let Some(connection) = Self::upgrade(weak_inner) {
+                    connection.connection_lost(error, allow_reconnect);
+                }
+                return false;
+            }
+            ProtocolEvent::HandshakeCompleted | ProtocolEvent::HandshakeFailed(_) => {
+                log_warn!(log, "Unexpected event after the handshake: {:?}", event);
+            }
+        }
 
-        Ok(())
+        true
     }
 
-    pub(super) async fn handle_completion(
+    pub(super) fn handle_completion(
         message: CompletionMessage,
-        mut subscribers: RefMut<'_, CompletionSubscriberMap>,
+        subscribers: &RefCell<CompletionSubscriberMap>,
     ) -> Result<(), String> {
-        let sender = match subscribers.get_mut(&message.invocation_id) {
-            Some(s) => s,
+        let pending = match subscribers.borrow_mut().remove(&message.invocation_id) {
+            Some(pending) => pending,
             None => {
                 return Err(format!(
                     "Failed to find subscriber for invocation ID {}",
@@ -59,30 +215,62 @@ impl SignalRConnection {
             }
         };
 
-        sender
-            .send(message)
-            .await
+        pending
+            .result
+            .send(Ok(message))
             .map_err(|_| "Failed to send subscriber message to subscriber".to_string())
     }
 
+    /// Passes `message` to its target's handler and streams, according to each one's overflow
+    /// policy. The senders are cloned out first, so handlers and streams can be registered
+    /// while this waits for room. Returns the id of any invocation the handler's queue dropped,
+    /// which is left for the caller to answer.
     pub(super) async fn handle_invocation(
         message: InvocationMessage,
-        mut subscribers: RefMut<'_, InvocationSubscriberMap>,
-    ) -> Result<(), String> {
-        let sender = match subscribers.get_mut(&message.target) {
-            Some(s) => s,
-            None => {
-                console_log!(
-                    "No handler registered for invocation target {}",
-                    message.target
-                );
-                return Ok(());
+        subscribers: &RefCell<InvocationSubscriberMap>,
+        streams: &RefCell<InvocationStreamMap>,
+        log: &Log,
+    ) -> Result<Option<String>, SignalRError> {
+        let handler = subscribers.borrow().get(&message.target).cloned();
+        let streams = match streams.borrow_mut().get_mut(&message.target) {
+            Some(streams) => {
+                // Streams that were dropped.
+                streams.retain(|s| !s.sender.is_closed());
+                streams.iter().map(|s| s.sender.clone()).collect()
             }
+            None => Vec::new(),
         };
 
-        sender
-            .send(message)
-            .await
-            .map_err(|_| "Failed to send subscriber message to subscriber".to_string())
+        if handler.is_none() && streams.is_empty() {
+            log_warn!(
+                log,
+                "No handler registered for invocation target {}",
+                message.target
+            );
+            return Ok(None);
+        }
+
+        let overflow = || SignalRError::Overflow {
+            target: message.target.clone(),
+        };
+
+        for sender in &streams {
+            match sender.send(message.clone()).await {
+                Err(SendError::Overflow) => return Err(overflow()),
+                // Closed since, which only means the stream was dropped in the meantime.
+                Ok(_) | Err(SendError::Closed) => {}
+            }
+        }
+
+        match handler {
+            Some(sender) => match sender.send(message.clone()).await {
+                Ok(dropped) => Ok(dropped.and_then(|m| m.invocation_id)),
+                Err(SendError::Overflow) => Err(overflow()),
+                Err(SendError::Closed) => Err("Failed to send subscriber message to subscriber"
+                    .to_owned()
+                    .into()),
+            },
+            None => Ok(None),
+        }
     }
 }
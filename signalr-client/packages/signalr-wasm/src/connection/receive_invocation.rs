@@ -1,38 +1,302 @@
-use crate::connection::{SignalRConnection, CHANNEL_BOUND_SIZE};
+use crate::connection::{
+    ConnectionInner, InvocationHandler, SignalRConnection, StreamSubscription,
+};
+use crate::error::SignalRError;
+use crate::log::Log;
 use crate::message::InvocationMessage;
-use futures::channel::mpsc;
-use futures::StreamExt;
+use crate::protocol::HubProtocolState;
+use futures::channel::oneshot;
+use futures::future;
+use futures::{FutureExt, Stream, StreamExt};
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::Value;
-use wasm_bindgen_futures::spawn_local;
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::future::Future;
+use std::rc::{Rc, Weak};
+
+/// What a handler returns. When the server asks for a result, it is sent back as the
+/// invocation's completion: `Ok` values and plain values as its result, `Err` as its error.
+pub trait HandlerResult {
+    fn into_result(self) -> Result<Value, String>;
+}
+
+impl HandlerResult for () {
+    fn into_result(self) -> Result<Value, String> {
+        Ok(Value::Null)
+    }
+}
+
+impl<T: Serialize, E: Display> HandlerResult for Result<T, E> {
+    fn into_result(self) -> Result<Value, String> {
+        let value = self.map_err(|e| e.to_string())?;
+
+        serde_json::to_value(value).map_err(|e| format!("Failed to serialize result: {}", e))
+    }
+}
+
+macro_rules! handler_result {
+    ($($ty:ty),*) => {
+        $(
+            impl HandlerResult for $ty {
+                fn into_result(self) -> Result<Value, String> {
+                    Ok::<_, String>(self).into_result()
+                }
+            }
+        )*
+    };
+}
+
+handler_result!(bool, i8, i16, i32, i64, u8, u16, u32, u64, f32, f64, String, Value);
+
+/// How an async handler's invocations are run. Either way they're started in the order they
+/// were received.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HandlerExecution {
+    /// Each invocation waits for the previous one to finish.
+    #[default]
+    Sequential,
+    /// Invocations start as soon as they're received and may finish in any order.
+    Concurrent,
+}
 
 impl SignalRConnection {
-    pub fn on<T1, T2>(&mut self, method_name: &str, handler: impl Fn(T1, T2) -> () + 'static)
+    /// Calls `handler` whenever the server invokes `method_name`. If the server asks for a
+    /// result, the handler's return value is sent back; see [`HandlerResult`].
+    pub fn on<T1, T2, R>(&self, method_name: &str, handler: impl Fn(T1, T2) -> R + 'static)
     where
         T1: DeserializeOwned + 'static,
         T2: DeserializeOwned + 'static,
+        R: HandlerResult + 'static,
+    {
+        self.on_invocation(method_name, move |arguments| {
+            Self::call_handler(arguments, &handler).and_then(HandlerResult::into_result)
+        })
+    }
+
+    /// Like [`SignalRConnection::on`], for handlers returning a future. Its output is the
+    /// handler's result.
+    pub fn on_async<T1, T2, Fut, R>(
+        &self,
+        method_name: &str,
+        execution: HandlerExecution,
+        handler: impl Fn(T1, T2) -> Fut + 'static,
+    ) where
+        T1: DeserializeOwned + 'static,
+        T2: DeserializeOwned + 'static,
+        Fut: Future<Output = R> + 'static,
+        R: HandlerResult + 'static,
+    {
+        self.on_invocation_async(method_name, execution, move |arguments| {
+            let called = Self::call_handler(arguments, &handler);
+
+            async move { called?.await.into_result() }
+        })
+    }
+
+    /// Yields the arguments of every invocation of `method_name` as `Args`, usually a tuple
+    /// with one element per argument. Invocations whose arguments don't deserialize are
+    /// logged and skipped. When the connection closes or starts reconnecting, the stream
+    /// yields [`SignalRError::ConnectionClosed`] after any invocations it received and ends.
+    pub fn on_stream<Args>(
+        &self,
+        method_name: &str,
+    ) -> impl Stream<Item = Result<Args, SignalRError>> + Unpin
+    where
+        Args: DeserializeOwned + 'static,
+    {
+        let (sender, receiver) = self.inner.subscribe(method_name);
+        let (closed, closed_receiver) = oneshot::channel();
+
+        self.inner
+            .invocation_streams
+            .borrow_mut()
+            .entry(method_name.to_owned())
+            .or_default()
+            .push(StreamSubscription { sender, closed });
+
+        let closed = closed_receiver
+            .into_stream()
+            .filter_map(|error| future::ready(error.ok().map(Err)));
+
+        let log = self.inner.log.clone();
+        let invocations = receiver.filter_map(move |invocation| {
+            let InvocationMessage {
+                target, arguments, ..
+            } = invocation;
+            let arguments = serde_json::from_value(Value::Array(arguments))
+                .map_err(|e| {
+                    log_error!(
+                        log,
+                        "Failed to deserialize arguments for `{}`: {}",
+                        target,
+                        e
+                    )
+                })
+                .ok();
+
+            future::ready(arguments.map(Ok))
+        });
+
+        invocations.chain(closed)
+    }
+
+    /// Like [`SignalRConnection::on`], but passes the raw arguments through without
+    /// deserializing them.
+    pub fn on_invocation<R>(&self, method_name: &str, handler: impl Fn(Vec<Value>) -> R + 'static)
+    where
+        R: HandlerResult + 'static,
     {
-        let (sender, mut receiver) = mpsc::channel::<InvocationMessage>(CHANNEL_BOUND_SIZE);
+        self.on_invocation_async(
+            method_name,
+            HandlerExecution::Sequential,
+            move |arguments| future::ready(handler(arguments)),
+        )
+    }
+
+    /// Like [`SignalRConnection::on_async`], but passes the raw arguments through without
+    /// deserializing them.
+    pub fn on_invocation_async<F, Fut, R>(
+        &self,
+        method_name: &str,
+        execution: HandlerExecution,
+        handler: F,
+    ) where
+        F: Fn(Vec<Value>) -> Fut + 'static,
+        Fut: Future<Output = R> + 'static,
+        R: HandlerResult + 'static,
+    {
+        self.on_message_async(method_name, execution, move |message| {
+            handler(message.arguments)
+        })
+    }
+
+    /// Like [`SignalRConnection::on_invocation`], but passes the whole message, for its
+    /// headers.
+    pub fn on_message<R>(
+        &self,
+        method_name: &str,
+        handler: impl Fn(InvocationMessage) -> R + 'static,
+    ) where
+        R: HandlerResult + 'static,
+    {
+        self.on_message_async(method_name, HandlerExecution::Sequential, move |message| {
+            future::ready(handler(message))
+        })
+    }
+
+    /// Like [`SignalRConnection::on_invocation_async`], but passes the whole message, for its
+    /// headers.
+    pub fn on_message_async<F, Fut, R>(
+        &self,
+        method_name: &str,
+        execution: HandlerExecution,
+        handler: F,
+    ) where
+        F: Fn(InvocationMessage) -> Fut + 'static,
+        Fut: Future<Output = R> + 'static,
+        R: HandlerResult + 'static,
+    {
+        let handler = InvocationHandler {
+            call: Rc::new(move |message| handler(message).map(R::into_result).boxed_local()),
+            execution,
+        };
+
+        self.inner
+            .handlers
+            .borrow_mut()
+            .insert(method_name.to_owned(), handler.clone());
+
+        let protocol = self.inner.protocol.borrow().clone();
+        if let Some(protocol) = protocol {
+            self.spawn_handler(method_name, handler, protocol);
+        }
+    }
+
+    /// Starts a task for every registered handler, answering invocations over `protocol`. The
+    /// tasks end when the connection is stopped and are started again by the next `connect`.
+    pub(super) fn spawn_handlers(&self, protocol: &Rc<RefCell<HubProtocolState>>) {
+        for (method_name, handler) in self.inner.handlers.borrow().iter() {
+            self.spawn_handler(method_name, handler.clone(), protocol.clone());
+        }
+    }
+
+    fn spawn_handler(
+        &self,
+        method_name: &str,
+        handler: InvocationHandler,
+        protocol: Rc<RefCell<HubProtocolState>>,
+    ) {
+        let (sender, mut receiver) = self.inner.subscribe(method_name);
 
         {
-            self.invocation_subscribers
+            self.inner
+                .invocation_subscribers
                 .borrow_mut()
                 .insert(method_name.to_owned(), sender);
         }
 
-        spawn_local(async move {
-            while let Some(invocation) = receiver.next().await {
-                if let Err(e) = Self::call_handler(invocation.arguments, &handler) {
-                    console_error!("Failed to invoke handler: {}", e);
+        let weak_inner = self.downgrade();
+        let runtime = self.inner.runtime.clone();
+        let log = self.inner.log.clone();
+
+        self.inner.runtime.spawn_local(
+            async move {
+                while let Some(invocation) = receiver.next().await {
+                    let call = Self::call(
+                        weak_inner.clone(),
+                        protocol.clone(),
+                        log.clone(),
+                        &handler,
+                        invocation,
+                    );
+
+                    match handler.execution {
+                        HandlerExecution::Sequential => call.await,
+                        HandlerExecution::Concurrent => runtime.spawn_local(call.boxed_local()),
+                    }
                 }
             }
-        })
+            .boxed_local(),
+        )
     }
 
-    fn call_handler<T1, T2>(
+    /// Runs `handler` for `invocation`, then sends its result over `protocol`, the attempt the
+    /// invocation arrived on, if the server asked for one.
+    fn call(
+        weak_inner: Weak<ConnectionInner>,
+        protocol: Rc<RefCell<HubProtocolState>>,
+        log: Log,
+        handler: &InvocationHandler,
+        invocation: InvocationMessage,
+    ) -> impl Future<Output = ()> {
+        let invocation_id = invocation.invocation_id.clone();
+        let result = (handler.call)(invocation);
+
+        async move {
+            let result = result.await;
+
+            match (invocation_id, weak_inner.upgrade()) {
+                (Some(invocation_id), Some(inner)) => {
+                    if let Err(e) = inner.complete(&protocol, invocation_id, result) {
+                        log_error!(log, "Failed to send handler result: {}", e);
+                    }
+                }
+                (Some(_), None) => {}
+                (None, _) => {
+                    if let Err(e) = result {
+                        log_error!(log, "Failed to invoke handler: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn call_handler<T1, T2, R>(
         mut args: Vec<Value>,
-        handler: impl Fn(T1, T2) -> (),
-    ) -> Result<(), String>
+        handler: impl Fn(T1, T2) -> R,
+    ) -> Result<R, String>
     where
         T1: DeserializeOwned,
         T2: DeserializeOwned,
@@ -53,8 +317,6 @@ impl SignalRConnection {
                     .map_err(|e| format!("Failed to deserialize argument `{}`", e))
             })?;
 
-        handler(arg1, arg2);
-
-        return Ok(());
+        Ok(handler(arg1, arg2))
     }
 }
@@ -0,0 +1,138 @@
+use crate::connection::{ConnectionInner, SignalRConnection};
+use crate::error::SignalRError;
+use crate::reconnect::RetryContext;
+use crate::state::ConnectionState;
+use futures::FutureExt;
+use std::rc::Weak;
+use std::time::Duration;
+
+impl SignalRConnection {
+    /// Handles the connection ending without `stop` being called, by reconnecting if the
+    /// reconnect policy and the server allow it and disconnecting otherwise.
+    pub(super) fn connection_lost(&self, error: SignalRError, allow_reconnect: bool) {
+        if self.inner.state.get() != ConnectionState::Connected {
+            // Already being stopped, or the loss has already been handled.
+            return;
+        }
+
+        self.inner.close(false, Some(error.to_string()), true);
+
+        // An expired token is worth one more try with a fresh one, even without a policy.
+        let refresh_token =
+            error.is_auth_failure() && self.inner.options.access_token_factory.is_some();
+        let reconnect =
+            (allow_reconnect && self.inner.options.reconnect_policy.is_some()) || refresh_token;
+        let next = if reconnect {
+            ConnectionState::Reconnecting
+        } else {
+            ConnectionState::Disconnected
+        };
+
+        if let Err(e) = self.inner.state.transition_with_error(
+            "handle the lost connection",
+            &[ConnectionState::Connected],
+            next,
+            Some(error.clone()),
+        ) {
+            log_error!(self.inner.log, "{}", e);
+            return;
+        }
+
+        if !reconnect {
+            let reason = Some(error.to_string());
+            self.inner
+                .fail_outbox(SignalRError::ConnectionClosed { reason });
+        }
+
+        if reconnect {
+            self.inner
+                .runtime
+                .spawn_local(Self::reconnect(self.downgrade(), error).boxed_local());
+        }
+    }
+
+    async fn reconnect(weak_inner: Weak<ConnectionInner>, error: SignalRError) {
+        let mut context = RetryContext {
+            previous_retry_count: 0,
+            retry_reason: error.to_string(),
+        };
+        let mut last_error = error;
+
+        loop {
+            let (policy, runtime, log) = match Self::upgrade(&weak_inner) {
+                Some(connection) => (
+                    connection.inner.options.reconnect_policy.clone(),
+                    connection.inner.runtime.clone(),
+                    connection.inner.log.clone(),
+                ),
+                None => return,
+            };
+            let delay = match policy {
+                Some(policy) => policy.next_retry_delay(&context),
+                None if context.previous_retry_count == 0 => Some(Duration::ZERO),
+                None => None,
+            };
+
+            let delay = match delay {
+                Some(delay) => delay,
+                None => break,
+            };
+
+            log_info!(
+                log,
+                "Reconnect attempt {} in {:?}",
+                context.previous_retry_count + 1,
+                delay
+            );
+            runtime.sleep(delay).await;
+
+            let connection = match Self::upgrade(&weak_inner) {
+                Some(connection) => connection,
+                None => return,
+            };
+
+            if connection.state() != ConnectionState::Reconnecting {
+                // Stopped while waiting.
+                return;
+            }
+
+            match connection.open(last_error.is_auth_failure()).await {
+                Ok(()) => {
+                    if let Err(e) = connection.inner.state.transition(
+                        "complete the reconnect",
+                        &[ConnectionState::Reconnecting],
+                        ConnectionState::Connected,
+                    ) {
+                        // Stopped while the attempt was in flight.
+                        log_warn!(connection.inner.log, "{}", e);
+                        connection.inner.close(true, None, false);
+                    } else {
+                        connection.inner.flush_outbox();
+                    }
+
+                    return;
+                }
+                Err(e) => {
+                    log_warn!(connection.inner.log, "Reconnect attempt failed: {}", e);
+                    context.previous_retry_count += 1;
+                    context.retry_reason = e.to_string();
+                    last_error = e;
+                }
+            }
+        }
+
+        if let Some(connection) = Self::upgrade(&weak_inner) {
+            let reason = Some(last_error.to_string());
+            connection
+                .inner
+                .fail_outbox(SignalRError::ConnectionClosed { reason });
+
+            let _ = connection.inner.state.transition_with_error(
+                "give up reconnecting",
+                &[ConnectionState::Reconnecting],
+                ConnectionState::Disconnected,
+                Some(last_error),
+            );
+        }
+    }
+}
@@ -1,62 +1,263 @@
-use crate::connection::{SignalRConnection, CHANNEL_BOUND_SIZE};
-use crate::message::{CompletionMessage, InvocationMessage};
-use futures::channel::mpsc;
-use futures::StreamExt;
+use crate::connection::{
+    ConnectionInner, InvocationRequest, PendingInvocation, ResultSender, SignalRConnection,
+};
+use crate::error::SignalRError;
+use crate::message::CompletionMessage;
+use crate::state::ConnectionState;
+use crate::trace::{ActiveSpan, TRACEPARENT_HEADER};
+use futures::channel::oneshot;
+use futures::future::Shared;
+use futures::FutureExt;
 use serde_json::Value;
-use web_sys::WebSocket;
+use std::collections::HashMap;
+use std::future::Future;
+
+/// Carries the key of idempotent invocations, the same every time one is sent, so the server
+/// can recognize retries. Keys are the connection's client id and a sequence number.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// How [`SignalRConnection::invoke_with`] sends an invocation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InvocationOptions {
+    /// Whether the hub method is safe to run more than once; see
+    /// [`SignalRConnection::send_idempotent_invocation`].
+    pub idempotent: bool,
+    /// Sent with the invocation, in addition to the connection's default headers.
+    pub headers: HashMap<String, String>,
+}
+
+/// A hub method call started by [`SignalRConnection::invoke`].
+pub struct Invocation {
+    queued: bool,
+    sent: Shared<oneshot::Receiver<Result<(), SignalRError>>>,
+    result: oneshot::Receiver<Result<CompletionMessage, SignalRError>>,
+}
+
+impl Invocation {
+    /// Whether the invocation was held in the offline queue rather than sent right away.
+    pub fn is_queued(&self) -> bool {
+        self.queued
+    }
+
+    /// Resolves once the invocation has been handed to the transport, which is immediately
+    /// unless it was queued.
+    pub fn sent(&self) -> impl Future<Output = Result<(), SignalRError>> + 'static {
+        self.sent.clone().map(|sent| match sent {
+            Ok(result) => result,
+            Err(_) => Err(SignalRError::ConnectionClosed { reason: None }),
+        })
+    }
+
+    /// Resolves with the hub method's return value.
+    pub fn result(self) -> impl Future<Output = Result<Value, SignalRError>> + 'static {
+        self.completion().map(|message| {
+            let message = message?;
+
+            match message.error {
+                Some(error) => Err(format!("Invocation failed on the server: {}", error).into()),
+                None => Ok(message.result),
+            }
+        })
+    }
+
+    /// Resolves with the server's completion message, for its headers. Unlike
+    /// [`Invocation::result`], a hub method error is left in the message.
+    pub fn completion(
+        self,
+    ) -> impl Future<Output = Result<CompletionMessage, SignalRError>> + 'static {
+        self.result.map(|message| {
+            let message = match message {
+                Ok(result) => result?,
+                Err(_) => return Err(SignalRError::ConnectionClosed { reason: None }),
+            };
+
+            Ok(message)
+        })
+    }
+}
 
 impl SignalRConnection {
+    /// Invokes `target` on the hub and waits for its result. Fails with
+    /// [`SignalRError::ConnectionClosed`] if the connection closes or starts reconnecting first.
     pub async fn send_invocation(
-        &mut self,
+        &self,
         target: String,
         args: Vec<Value>,
-    ) -> Result<(), String> {
-        let ws: &WebSocket = match self.web_socket.get_mut() {
-            Some(ws) => ws,
-            None => {
-                return Err("No open socket".to_owned());
-            }
+    ) -> Result<Value, SignalRError> {
+        self.invoke(target, args)?.result().await
+    }
+
+    /// Like [`SignalRConnection::send_invocation`], for hub methods that are safe to run more
+    /// than once. If the connection is lost before the result arrives, the invocation is sent
+    /// again after reconnecting, up to
+    /// [`invocation_retries`](crate::ConnectionOptions::invocation_retries) times.
+    pub async fn send_idempotent_invocation(
+        &self,
+        target: String,
+        args: Vec<Value>,
+    ) -> Result<Value, SignalRError> {
+        self.invoke_idempotent(target, args)?.result().await
+    }
+
+    /// Starts invoking `target` on the hub. While connecting or reconnecting, the invocation
+    /// is held in the offline queue if one is configured, and fails straight away otherwise.
+    pub fn invoke(&self, target: String, args: Vec<Value>) -> Result<Invocation, SignalRError> {
+        self.invoke_with(target, args, InvocationOptions::default())
+    }
+
+    /// Like [`SignalRConnection::invoke`], marking the invocation idempotent; see
+    /// [`SignalRConnection::send_idempotent_invocation`].
+    pub fn invoke_idempotent(
+        &self,
+        target: String,
+        args: Vec<Value>,
+    ) -> Result<Invocation, SignalRError> {
+        let options = InvocationOptions {
+            idempotent: true,
+            ..InvocationOptions::default()
         };
 
-        self.invocation_id += 1;
+        self.invoke_with(target, args, options)
+    }
 
-        let invocation = InvocationMessage::new(self.invocation_id.to_string(), target, args);
+    /// Like [`SignalRConnection::invoke`], with `options` for how it's sent.
+    pub fn invoke_with(
+        &self,
+        target: String,
+        args: Vec<Value>,
+        options: InvocationOptions,
+    ) -> Result<Invocation, SignalRError> {
+        let idempotency_key = if options.idempotent {
+            let key = self.inner.last_idempotency_key.get() + 1;
+            self.inner.last_idempotency_key.set(key);
 
-        Self::send_struct(ws, &invocation)
-            .map_err(|e| format!("Failed to send message: {:?}", e))?;
+            Some(format!("{}:{}", self.inner.client_id, key))
+        } else {
+            None
+        };
 
-        self.await_invocation_response(invocation.invocation_id)
-            .await?;
+        let mut headers = options.headers;
+        // A context set by the caller is sent as is, without a span of our own.
+        let span = match &self.inner.options.trace_provider {
+            Some(provider) if !headers.contains_key(TRACEPARENT_HEADER) => {
+                let span = ActiveSpan::start(provider.clone(), self.inner.runtime.clone(), &target);
+                span.context().inject(&mut headers);
 
-        Ok(())
+                Some(span)
+            }
+            _ => None,
+        };
+
+        self.start(
+            InvocationRequest {
+                target,
+                arguments: args,
+                headers,
+                idempotency_key,
+                retries: 0,
+            },
+            span,
+        )
     }
 
-    async fn await_invocation_response(&mut self, invocation_id: String) -> Result<(), String> {
-        let (sender, mut receiver) = mpsc::channel::<CompletionMessage>(CHANNEL_BOUND_SIZE);
+    fn start(
+        &self,
+        request: InvocationRequest,
+        span: Option<ActiveSpan>,
+    ) -> Result<Invocation, SignalRError> {
+        let (sent_sender, sent) = oneshot::channel();
+        let (sender, result) = oneshot::channel();
+        let result_sender = ResultSender { sender, span };
 
-        {
-            self.completion_subscribers
-                .borrow_mut()
-                .insert(invocation_id.clone(), sender);
-        }
+        let state = self.inner.state.get();
+        let queued = match state {
+            ConnectionState::Connected => {
+                self.inner.start_invocation(request, result_sender)?;
+                let _ = sent_sender.send(Ok(()));
 
-        console_log!("Waiting for response");
+                false
+            }
+            ConnectionState::Connecting | ConnectionState::Reconnecting
+                if self.inner.options.offline_queue.is_some() =>
+            {
+                self.enqueue(request, sent_sender, result_sender)?;
 
-        // TODO: consider timeout
-        let message = receiver.next().await;
+                true
+            }
+            _ => {
+                let error = SignalRError::InvalidState {
+                    operation: "invoke a hub method",
+                    state,
+                };
+                let _ = result_sender.send(Err(error.clone()));
 
-        {
-            self.completion_subscribers
+                return Err(error);
+            }
+        };
+
+        Ok(Invocation {
+            queued,
+            sent: sent.shared(),
+            result,
+        })
+    }
+}
+
+impl ConnectionInner {
+    /// Sends `request`, to have its completion sent to `result`. If that fails, the error is
+    /// sent there too.
+    pub(super) fn start_invocation(
+        &self,
+        request: InvocationRequest,
+        result: ResultSender,
+    ) -> Result<(), SignalRError> {
+        let retry = request.idempotency_key.is_some().then(|| request.clone());
+
+        let invocation_id = match self.queue_request(request) {
+            Ok(invocation_id) => invocation_id,
+            Err(error) => {
+                let _ = result.send(Err(error.clone()));
+                return Err(error);
+            }
+        };
+
+        // Subscribed before the frame goes out, so the completion can't arrive first.
+        self.completion_subscribers
+            .borrow_mut()
+            .insert(invocation_id.clone(), PendingInvocation { result, retry });
+
+        if let Err(e) = self.flush() {
+            let error = SignalRError::from(format!("Failed to send message: {:?}", e));
+            let pending = self
+                .completion_subscribers
                 .borrow_mut()
                 .remove(&invocation_id);
-        }
 
-        if message.is_none() {
-            return Err("Failed to receive message".to_owned());
+            if let Some(pending) = pending {
+                let _ = pending.result.send(Err(error.clone()));
+            }
+            return Err(error);
         }
 
-        console_log!("Received invocation reply: {:?}", message);
-
         Ok(())
     }
+
+    /// Queues an invocation with the protocol, returning the id its completion will carry.
+    fn queue_request(&self, request: InvocationRequest) -> Result<String, SignalRError> {
+        let mut headers = request.headers;
+        if let Some(key) = request.idempotency_key {
+            headers.insert(IDEMPOTENCY_KEY_HEADER.to_owned(), key);
+        }
+        let headers = self.message_headers(headers);
+
+        match self.protocol.borrow().as_ref() {
+            Some(protocol) => Ok(protocol.borrow_mut().invoke_with_headers(
+                request.target,
+                request.arguments,
+                headers,
+                self.runtime.now(),
+            )?),
+            None => Err("Not connected".to_owned().into()),
+        }
+    }
 }
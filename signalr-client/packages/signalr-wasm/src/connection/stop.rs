@@ -0,0 +1,107 @@
+use crate::connection::{transmit, ConnectionInner, SignalRConnection};
+use crate::error::SignalRError;
+use crate::state::ConnectionState;
+use crate::transport::NORMAL_CLOSURE;
+
+impl SignalRConnection {
+    /// Closes the connection, optionally telling the server first with a Close message.
+    /// Pending invocations fail with [`SignalRError::ConnectionClosed`]. Stopping a
+    /// connection that is already disconnected does nothing, and stopping one that is still
+    /// connecting aborts [`connect`](Self::connect).
+    pub async fn stop(&self, send_close_message: bool) -> Result<(), SignalRError> {
+        if self.inner.state.get() == ConnectionState::Disconnected {
+            return Ok(());
+        }
+
+        self.inner.state.transition(
+            "stop",
+            &[
+                ConnectionState::Connecting,
+                ConnectionState::Connected,
+                ConnectionState::Reconnecting,
+            ],
+            ConnectionState::Disconnecting,
+        )?;
+
+        if let Some(cancel) = self.inner.cancel_connect.borrow_mut().take() {
+            let _ = cancel.send(());
+        }
+
+        self.inner.close(send_close_message, None, false);
+        self.inner
+            .fail_outbox(SignalRError::ConnectionClosed { reason: None });
+
+        self.inner.state.transition(
+            "stop",
+            &[ConnectionState::Disconnecting],
+            ConnectionState::Disconnected,
+        )?;
+
+        Ok(())
+    }
+}
+
+impl ConnectionInner {
+    /// Closes the transport and releases everything attached to it: the transport's events,
+    /// which ends the reader task, the handler tasks, and any pending invocations and streams,
+    /// which fail with `reason`. With `requeue`, idempotent invocations with retries left are
+    /// queued to be sent again instead.
+    pub(super) fn close(&self, send_close_message: bool, reason: Option<String>, requeue: bool) {
+        let protocol = self.protocol.borrow_mut().take();
+        let transport = self.transport.borrow_mut().take();
+
+        if let Some(protocol) = protocol {
+            let mut protocol = protocol.borrow_mut();
+            protocol.close(None, self.runtime.now());
+
+            if let (true, Some(transport)) = (send_close_message, &transport) {
+                if let Err(e) = transmit(&mut protocol, transport.as_ref()) {
+                    log_error!(self.log, "Failed to send close message: {}", e);
+                }
+            }
+        }
+
+        if let Some(transport) = transport {
+            transport.close(NORMAL_CLOSURE);
+        }
+
+        self.invocation_subscribers.borrow_mut().clear();
+
+        let error = SignalRError::ConnectionClosed { reason };
+        let mut completions: Vec<_> = self.completion_subscribers.borrow_mut().drain().collect();
+        let streams: Vec<_> = self.invocation_streams.borrow_mut().drain().collect();
+
+        // Retried in the order they were first sent. Ids count up from 1.
+        completions.sort_by_key(|(id, _)| id.parse::<u64>().unwrap_or(u64::MAX));
+        let mut retries = Vec::new();
+
+        for (_, pending) in completions {
+            match pending.retry {
+                Some(mut request)
+                    if requeue && request.retries < self.options.invocation_retries =>
+                {
+                    request.retries += 1;
+                    retries.push((request, pending.result));
+                }
+                _ => {
+                    let _ = pending.result.send(Err(error.clone()));
+                }
+            }
+        }
+
+        // Failed along with everything else queued if the connection isn't reestablished.
+        self.outbox.borrow_mut().requeue(retries);
+
+        for subscription in streams.into_iter().flat_map(|(_, streams)| streams) {
+            let _ = subscription.closed.send(error.clone());
+        }
+    }
+}
+
+impl Drop for ConnectionInner {
+    /// Fails everything still pending rather than leaving it queued, so every span ends.
+    fn drop(&mut self) {
+        self.close(true, None, false);
+        self.fail_outbox(SignalRError::ConnectionClosed { reason: None });
+    }
+}
@@ -0,0 +1,178 @@
+//! The bounded queue between the reader task and a handler or stream. Unlike an `mpsc`
+//! channel it can make room by dropping messages, depending on its [`OverflowPolicy`].
+
+use futures::Stream;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use crate::builder::OverflowPolicy;
+
+/// Why a message couldn't be queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum SendError {
+    /// The receiving end was dropped.
+    Closed,
+    /// The queue was full and its policy is [`OverflowPolicy::Disconnect`].
+    Overflow,
+}
+
+struct Shared<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    senders: usize,
+    receiver_closed: bool,
+    receiver_waker: Option<Waker>,
+    /// Senders waiting for room under [`OverflowPolicy::Block`].
+    sender_wakers: Vec<Waker>,
+}
+
+/// Creates a queue holding up to `capacity` messages. Messages dropped to make room are
+/// counted in `dropped`.
+pub(super) fn channel<T>(
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: Rc<Cell<u64>>,
+) -> (Sender<T>, Receiver<T>) {
+    let shared = Rc::new(RefCell::new(Shared {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        policy,
+        senders: 1,
+        receiver_closed: false,
+        receiver_waker: None,
+        sender_wakers: Vec::new(),
+    }));
+
+    (
+        Sender {
+            shared: shared.clone(),
+            dropped,
+        },
+        Receiver { shared },
+    )
+}
+
+pub(super) struct Sender<T> {
+    shared: Rc<RefCell<Shared<T>>>,
+    dropped: Rc<Cell<u64>>,
+}
+
+impl<T> Sender<T> {
+    pub fn is_closed(&self) -> bool {
+        self.shared.borrow().receiver_closed
+    }
+
+    /// Queues `message`, waiting for room first if the policy is [`OverflowPolicy::Block`].
+    /// Resolves with the message dropped to make room, if any: the oldest one under
+    /// [`OverflowPolicy::DropOldest`], or `message` itself under [`OverflowPolicy::DropNewest`].
+    pub fn send(&self, message: T) -> impl Future<Output = Result<Option<T>, SendError>> + '_ {
+        let mut message = Some(message);
+
+        poll_fn(move |cx| {
+            let mut shared = self.shared.borrow_mut();
+            let mut dropped = None;
+
+            if shared.receiver_closed {
+                return Poll::Ready(Err(SendError::Closed));
+            }
+
+            if shared.queue.len() >= shared.capacity {
+                match shared.policy {
+                    OverflowPolicy::Block => {
+                        shared.sender_wakers.push(cx.waker().clone());
+                        return Poll::Pending;
+                    }
+                    OverflowPolicy::DropOldest => {
+                        dropped = shared.queue.pop_front();
+                        self.dropped.set(self.dropped.get() + 1);
+                    }
+                    OverflowPolicy::DropNewest => {
+                        self.dropped.set(self.dropped.get() + 1);
+                        return Poll::Ready(Ok(message.take()));
+                    }
+                    OverflowPolicy::Disconnect => {
+                        self.dropped.set(self.dropped.get() + 1);
+                        return Poll::Ready(Err(SendError::Overflow));
+                    }
+                }
+            }
+
+            if let Some(message) = message.take() {
+                shared.queue.push_back(message);
+            }
+            if let Some(waker) = shared.receiver_waker.take() {
+                waker.wake();
+            }
+
+            Poll::Ready(Ok(dropped))
+        })
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.borrow_mut().senders += 1;
+
+        Self {
+            shared: self.shared.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.senders -= 1;
+
+        if shared.senders == 0 {
+            if let Some(waker) = shared.receiver_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+pub(super) struct Receiver<T> {
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut shared = self.shared.borrow_mut();
+
+        match shared.queue.pop_front() {
+            Some(message) => {
+                for waker in shared.sender_wakers.drain(..) {
+                    waker.wake();
+                }
+
+                Poll::Ready(Some(message))
+            }
+            None if shared.senders == 0 => Poll::Ready(None),
+            None => {
+                shared.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.receiver_closed = true;
+        shared.queue.clear();
+
+        for waker in shared.sender_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,1626 @@
+use futures::future::{self, Either, LocalBoxFuture};
+use futures::stream::LocalBoxStream;
+use futures::{FutureExt, StreamExt};
+use serde_json::{json, Value};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::pin;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::builder::{HubConnectionBuilder, OverflowPolicy, SubscriptionOptions};
+use crate::connection::{HandlerExecution, InvocationOptions, SignalRConnection};
+use crate::error::{CloseInfo, SignalRError};
+use crate::log::LogLevel;
+use crate::message::InvocationMessage;
+use crate::runtime::Runtime;
+use crate::state::ConnectionState;
+use crate::trace::{InvocationSpan, TraceContext, TraceProvider};
+use crate::transport::{MemoryListener, MemoryPeer, MemoryTransport, Transport, TransportEvent};
+
+/// Runs on tokio without the `tokio-runtime` feature, whose clock starts paused so timeouts
+/// elapse as soon as everything is idle.
+struct TestRuntime {
+    start: tokio::time::Instant,
+}
+
+impl Runtime for TestRuntime {
+    fn spawn_local(&self, future: LocalBoxFuture<'static, ()>) {
+        tokio::task::spawn_local(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed_local()
+    }
+
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+fn run(test: impl Future<Output = ()>) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .unwrap();
+
+    tokio::task::LocalSet::new().block_on(&runtime, test);
+}
+
+/// Ends its events without a close event once the server sends `end`.
+struct EndingTransport(MemoryTransport);
+
+impl Transport for EndingTransport {
+    fn connect<'a>(
+        &'a mut self,
+        url: &'a str,
+        headers: &'a HashMap<String, String>,
+    ) -> LocalBoxFuture<'a, Result<LocalBoxStream<'static, TransportEvent>, SignalRError>> {
+        async move {
+            let events = self.0.connect(url, headers).await?;
+            let end = TransportEvent::Text("end".to_owned());
+
+            Ok(events
+                .take_while(move |event| future::ready(*event != end))
+                .boxed_local())
+        }
+        .boxed_local()
+    }
+
+    fn send_text(&self, text: &str) -> Result<(), String> {
+        self.0.send_text(text)
+    }
+
+    fn send_binary(&self, data: &[u8]) -> Result<(), String> {
+        self.0.send_binary(data)
+    }
+
+    fn close(&self, code: u16) {
+        self.0.close(code)
+    }
+}
+
+/// Never finishes opening.
+struct StalledTransport;
+
+impl Transport for StalledTransport {
+    fn connect<'a>(
+        &'a mut self,
+        _: &'a str,
+        _: &'a HashMap<String, String>,
+    ) -> LocalBoxFuture<'a, Result<LocalBoxStream<'static, TransportEvent>, SignalRError>> {
+        future::pending().boxed_local()
+    }
+
+    fn send_text(&self, _: &str) -> Result<(), String> {
+        Err("Not connected".to_owned())
+    }
+
+    fn send_binary(&self, _: &[u8]) -> Result<(), String> {
+        Err("Not connected".to_owned())
+    }
+
+    fn close(&self, _: u16) {}
+}
+
+fn builder(listener: &MemoryListener) -> HubConnectionBuilder {
+    HubConnectionBuilder::new()
+        .with_url("https://example.com/hub")
+        .with_transport(listener.connector())
+        .with_runtime(TestRuntime {
+            start: tokio::time::Instant::now(),
+        })
+}
+
+fn connection(listener: &MemoryListener) -> SignalRConnection {
+    builder(listener).build().unwrap()
+}
+
+/// Accepts a connection, checks the handshake request and answers with `response`.
+async fn accept(listener: &mut MemoryListener, response: &str) -> MemoryPeer {
+    let mut peer = listener.accept().await.unwrap();
+
+    assert_eq!(
+        peer.receive().await,
+        Some(TransportEvent::Text(
+            "{\"protocol\":\"json\",\"version\":1}\x1E".to_owned()
+        ))
+    );
+    peer.send_text(response).unwrap();
+
+    peer
+}
+
+async fn receive_message(peer: &mut MemoryPeer) -> Value {
+    match peer.receive().await {
+        Some(TransportEvent::Text(text)) => {
+            serde_json::from_str(text.strip_suffix('\x1E').unwrap()).unwrap()
+        }
+        other => panic!("Expected a message, got {:?}", other),
+    }
+}
+
+fn send_message(peer: &MemoryPeer, message_type: u8, mut fields: Value) {
+    fields["type"] = json!(message_type);
+    peer.send_text(&format!("{}\x1E", fields)).unwrap();
+}
+
+/// Invokes a client method, with `fields` giving its target, arguments and so on.
+fn send_invocation(peer: &MemoryPeer, fields: Value) {
+    send_message(peer, 1, fields);
+}
+
+/// Completes `invocation`, as returned by [`receive_message`], with `fields` giving its
+/// result or error.
+fn send_completion(peer: &MemoryPeer, invocation: &Value, mut fields: Value) {
+    fields["invocationId"] = invocation["invocationId"].clone();
+    send_message(peer, 3, fields);
+}
+
+#[test]
+fn connect_completes_the_handshake() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+
+        let (result, peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(connection.state(), ConnectionState::Connected);
+        assert_eq!(peer.url(), "wss://example.com/hub");
+    });
+}
+
+#[test]
+fn connect_fails_on_a_handshake_error() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+
+        let (result, _peer) = futures::join!(
+            connection.connect(),
+            accept(&mut listener, "{\"error\":\"Unsupported protocol\"}\x1E")
+        );
+
+        assert_eq!(
+            result,
+            Err(SignalRError::Other(
+                "Handshake failed: Received handshake error: Unsupported protocol".to_owned()
+            ))
+        );
+        assert_eq!(connection.state(), ConnectionState::Disconnected);
+    });
+}
+
+#[test]
+fn connecting_twice_fails() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+        let (_, _peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        assert_eq!(
+            connection.connect().await,
+            Err(SignalRError::InvalidState {
+                operation: "start",
+                state: ConnectionState::Connected,
+            })
+        );
+        assert_eq!(connection.state(), ConnectionState::Connected);
+    });
+}
+
+#[test]
+fn invoking_while_disconnected_fails() {
+    run(async {
+        let listener = MemoryListener::new();
+        let connection = connection(&listener);
+
+        assert_eq!(
+            connection.send_invocation("Add".to_owned(), vec![]).await,
+            Err(SignalRError::InvalidState {
+                operation: "invoke a hub method",
+                state: ConnectionState::Disconnected,
+            })
+        );
+    });
+}
+
+#[test]
+fn opening_the_transport_times_out() {
+    run(async {
+        let connection = HubConnectionBuilder::new()
+            .with_url("https://example.com/hub")
+            .with_transport(|| StalledTransport)
+            .with_runtime(TestRuntime {
+                start: tokio::time::Instant::now(),
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            connection.connect().await,
+            Err(SignalRError::HandshakeTimeout(Duration::from_secs(15)))
+        );
+        assert_eq!(connection.state(), ConnectionState::Disconnected);
+    });
+}
+
+#[test]
+fn dropping_connect_mid_handshake_closes_the_transport() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+
+        let handshake_sent = async {
+            let mut peer = listener.accept().await.unwrap();
+            peer.receive().await.unwrap();
+            peer
+        };
+        let mut peer =
+            match future::select(connection.connect().boxed_local(), pin!(handshake_sent)).await {
+                Either::Right((peer, connect)) => {
+                    drop(connect);
+                    peer
+                }
+                Either::Left((result, _)) => panic!("Connected without a handshake: {:?}", result),
+            };
+
+        assert_eq!(
+            peer.receive().await,
+            Some(TransportEvent::Closed(CloseInfo {
+                code: 1000,
+                reason: String::new(),
+                was_clean: true,
+            }))
+        );
+        assert_eq!(connection.state(), ConnectionState::Disconnected);
+    });
+}
+
+#[test]
+fn stopping_mid_handshake_aborts_connect() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+
+        let stop_once_handshake_sent = async {
+            let mut peer = listener.accept().await.unwrap();
+            peer.receive().await.unwrap();
+            assert_eq!(connection.stop(true).await, Ok(()));
+            assert_eq!(connection.state(), ConnectionState::Disconnected);
+            peer
+        };
+        let (result, mut peer) = future::join(connection.connect(), stop_once_handshake_sent).await;
+
+        assert_eq!(result, Err(SignalRError::Aborted));
+        assert_eq!(
+            peer.receive().await,
+            Some(TransportEvent::Closed(CloseInfo {
+                code: 1000,
+                reason: String::new(),
+                was_clean: true,
+            }))
+        );
+        assert_eq!(connection.state(), ConnectionState::Disconnected);
+    });
+}
+
+/// Counts the tokens handed out, which are `token-1`, `token-2` and so on.
+fn token_builder(listener: &MemoryListener) -> (HubConnectionBuilder, Rc<RefCell<Vec<bool>>>) {
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let factory_calls = calls.clone();
+    let builder = builder(listener).with_access_token_factory(move |force_refresh| {
+        factory_calls.borrow_mut().push(force_refresh);
+        future::ready(Ok(format!("token-{}", factory_calls.borrow().len())))
+    });
+
+    (builder, calls)
+}
+
+#[test]
+fn access_tokens_are_sent_when_opening_the_transport() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (builder, calls) = token_builder(&listener);
+        let connection = builder.build().unwrap();
+
+        let (result, peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+        assert_eq!(result, Ok(()));
+
+        assert_eq!(peer.headers()["Authorization"], "Bearer token-1");
+        if cfg!(feature = "native") {
+            assert_eq!(peer.url(), "wss://example.com/hub");
+        } else {
+            assert_eq!(peer.url(), "wss://example.com/hub?access_token=token-1");
+        }
+        assert_eq!(*calls.borrow(), [false]);
+    });
+}
+
+#[test]
+fn reconnecting_asks_for_a_new_access_token() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (builder, calls) = token_builder(&listener);
+        let connection = builder.with_automatic_reconnect().build().unwrap();
+
+        let (_, peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+        peer.close(1011, "Server error");
+
+        let peer = accept(&mut listener, "{}\x1E").await;
+        assert_eq!(peer.headers()["Authorization"], "Bearer token-2");
+        assert_eq!(*calls.borrow(), [false, false]);
+    });
+}
+
+/// Accepts a connection and closes it during the handshake as if the token were rejected,
+/// returning the `Authorization` header it was opened with.
+async fn reject(listener: &mut MemoryListener) -> Option<String> {
+    let mut peer = listener.accept().await.unwrap();
+    peer.receive().await.unwrap();
+
+    let authorization = peer.headers().get("Authorization").cloned();
+    peer.close(1008, "Unauthorized");
+
+    authorization
+}
+
+#[test]
+fn rejected_tokens_are_refreshed() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (builder, calls) = token_builder(&listener);
+        let connection = builder.build().unwrap();
+
+        let server = async {
+            assert_eq!(reject(&mut listener).await.unwrap(), "Bearer token-1");
+            accept(&mut listener, "{}\x1E").await
+        };
+        let (result, peer) = futures::join!(connection.connect(), server);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(peer.headers()["Authorization"], "Bearer token-2");
+        assert_eq!(*calls.borrow(), [false, true]);
+    });
+}
+
+#[test]
+fn refreshing_stops_after_the_configured_attempts() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (builder, calls) = token_builder(&listener);
+        let connection = builder.with_token_refresh_attempts(1).build().unwrap();
+
+        let server = async {
+            reject(&mut listener).await;
+            reject(&mut listener).await;
+        };
+        let (result, ()) = futures::join!(connection.connect(), server);
+
+        assert!(matches!(result, Err(SignalRError::Unauthorized(_))));
+        assert_eq!(*calls.borrow(), [false, true]);
+        assert_eq!(connection.state(), ConnectionState::Disconnected);
+    });
+}
+
+#[test]
+fn rejections_are_not_retried_without_a_token_factory() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+
+        let (result, authorization) = futures::join!(connection.connect(), reject(&mut listener));
+
+        assert_eq!(authorization, None);
+        assert!(matches!(result, Err(SignalRError::Unauthorized(_))));
+        assert!(listener.accept().now_or_never().is_none());
+    });
+}
+
+#[test]
+fn invocations_complete_with_the_server_result() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        let server = async {
+            let invocation = receive_message(&mut peer).await;
+            assert_eq!(invocation["target"], "Add");
+            assert_eq!(invocation["arguments"], json!([1, 2]));
+
+            send_completion(&peer, &invocation, json!({ "result": 3 }));
+        };
+
+        let (result, ()) = futures::join!(
+            connection.send_invocation("Add".to_owned(), vec![json!(1), json!(2)]),
+            server
+        );
+
+        assert_eq!(result, Ok(json!(3)));
+    });
+}
+
+#[test]
+fn handlers_receive_invocations_sent_with_the_handshake() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+
+        connection.on("ReceiveMessage", move |user: String, message: String| {
+            sender.unbounded_send((user, message)).unwrap();
+        });
+
+        let invocation = json!({
+            "type": 1,
+            "target": "ReceiveMessage",
+            "arguments": ["alice", "hello"],
+        });
+        let response = format!("{{}}\x1E{}\x1E", invocation);
+        let (result, _peer) =
+            futures::join!(connection.connect(), accept(&mut listener, &response));
+        assert_eq!(result, Ok(()));
+
+        assert_eq!(
+            receiver.next().await,
+            Some(("alice".to_owned(), "hello".to_owned()))
+        );
+    });
+}
+
+#[test]
+fn closing_the_transport_disconnects() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+        let mut state_changes = connection.state_changes();
+        let (_, peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        peer.close(1011, "Server error");
+
+        let last = loop {
+            let change = state_changes.next().await.unwrap();
+
+            if change.current == ConnectionState::Disconnected {
+                break change;
+            }
+        };
+
+        assert_eq!(
+            last.error,
+            Some(SignalRError::SocketClosed(CloseInfo {
+                code: 1011,
+                reason: "Server error".to_owned(),
+                was_clean: true,
+            }))
+        );
+        assert_eq!(connection.state(), ConnectionState::Disconnected);
+    });
+}
+
+#[test]
+fn stop_sends_a_close_message() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        assert_eq!(connection.stop(true).await, Ok(()));
+
+        assert_eq!(receive_message(&mut peer).await, json!({ "type": 7 }));
+        assert_eq!(
+            peer.receive().await,
+            Some(TransportEvent::Closed(CloseInfo {
+                code: 1000,
+                reason: String::new(),
+                was_clean: true,
+            }))
+        );
+        assert_eq!(connection.state(), ConnectionState::Disconnected);
+    });
+}
+
+#[test]
+fn keep_alive_pings_an_idle_server() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = builder(&listener)
+            .with_keep_alive_interval(Duration::from_secs(15))
+            .build()
+            .unwrap();
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        let started = tokio::time::Instant::now();
+        assert_eq!(receive_message(&mut peer).await, json!({ "type": 6 }));
+        assert_eq!(started.elapsed(), Duration::from_secs(15));
+    });
+}
+
+#[test]
+fn silent_server_times_out() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = builder(&listener)
+            .with_server_timeout(Duration::from_secs(30))
+            .build()
+            .unwrap();
+        let mut state_changes = connection.state_changes();
+        let (_, _peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        let last = loop {
+            let change = state_changes.next().await.unwrap();
+
+            if change.current == ConnectionState::Disconnected {
+                break change;
+            }
+        };
+
+        assert_eq!(
+            last.error,
+            Some(SignalRError::ServerTimeout(Duration::from_secs(30)))
+        );
+    });
+}
+
+#[test]
+fn streams_yield_invocation_arguments_until_closed() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+        let mut messages = connection.on_stream::<(String, String)>("ReceiveMessage");
+        let (_, peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        for arguments in [json!(["alice", "hello"]), json!([1]), json!(["bob", "hi"])] {
+            send_invocation(
+                &peer,
+                json!({ "target": "ReceiveMessage", "arguments": arguments }),
+            );
+        }
+
+        assert_eq!(
+            messages.next().await,
+            Some(Ok(("alice".to_owned(), "hello".to_owned())))
+        );
+        assert_eq!(
+            messages.next().await,
+            Some(Ok(("bob".to_owned(), "hi".to_owned())))
+        );
+
+        connection.stop(false).await.unwrap();
+        assert_eq!(
+            messages.next().await,
+            Some(Err(SignalRError::ConnectionClosed { reason: None }))
+        );
+        assert_eq!(messages.next().await, None);
+    });
+}
+
+#[test]
+fn handler_results_are_sent_back_to_the_server() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+
+        connection.on("Confirm", |action: String, count: u32| {
+            if action == "delete" {
+                Ok(count > 1)
+            } else {
+                Err(format!("Unknown action {}", action))
+            }
+        });
+
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        for (id, arguments) in [("1", json!(["delete", 2])), ("2", json!(["rename", 1]))] {
+            send_invocation(
+                &peer,
+                json!({ "invocationId": id, "target": "Confirm", "arguments": arguments }),
+            );
+        }
+
+        assert_eq!(
+            receive_message(&mut peer).await,
+            json!({ "type": 3, "invocationId": "1", "result": true })
+        );
+        assert_eq!(
+            receive_message(&mut peer).await,
+            json!({ "type": 3, "invocationId": "2", "error": "Unknown action rename" })
+        );
+    });
+}
+
+#[test]
+fn invocations_without_a_handler_are_answered_with_an_error() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        send_invocation(
+            &peer,
+            json!({ "invocationId": "1", "target": "Confirm", "arguments": [] }),
+        );
+
+        assert_eq!(
+            receive_message(&mut peer).await,
+            json!({ "type": 3, "invocationId": "1", "error": "Client didn't provide a result." })
+        );
+    });
+}
+
+#[test]
+fn handler_results_are_dropped_once_their_connection_is_lost() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (connection, peer) = retrying_connection(&mut listener, 0).await;
+        let (confirm, confirmed) = futures::channel::oneshot::channel::<bool>();
+        let confirmed = RefCell::new(Some(confirmed));
+
+        connection.on_async(
+            "Confirm",
+            HandlerExecution::Sequential,
+            move |_: String, _: u32| {
+                let confirmed = confirmed.borrow_mut().take().unwrap();
+                async move { confirmed.await.map_err(|e| e.to_string()) }
+            },
+        );
+
+        send_invocation(
+            &peer,
+            json!({ "invocationId": "1", "target": "Confirm", "arguments": ["delete", 2] }),
+        );
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        peer.close(1011, "Server error");
+
+        let mut peer = accept(&mut listener, "{}\x1E").await;
+        confirm.send(true).unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        let _ping = connection.invoke("Ping".to_owned(), vec![]).unwrap();
+        assert_eq!(receive_message(&mut peer).await["target"], "Ping");
+    });
+}
+
+/// Registers an async handler for `execution` that takes longer for earlier messages, sends
+/// it three, and returns the order they finished in.
+async fn finish_order(execution: HandlerExecution) -> Vec<u64> {
+    let mut listener = MemoryListener::new();
+    let connection = connection(&listener);
+    let (sender, receiver) = futures::channel::mpsc::unbounded();
+
+    connection.on_async("Work", execution, move |id: u64, delay: u64| {
+        let sender = sender.clone();
+
+        async move {
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            sender.unbounded_send(id).unwrap();
+        }
+    });
+
+    let (_, peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+    for (id, delay) in [(1, 3), (2, 2), (3, 1)] {
+        send_invocation(&peer, json!({ "target": "Work", "arguments": [id, delay] }));
+    }
+
+    receiver.take(3).collect().await
+}
+
+#[test]
+fn sequential_handlers_finish_in_wire_order() {
+    run(async {
+        assert_eq!(
+            finish_order(HandlerExecution::Sequential).await,
+            vec![1, 2, 3]
+        );
+    });
+}
+
+#[test]
+fn concurrent_handlers_overlap() {
+    run(async {
+        assert_eq!(
+            finish_order(HandlerExecution::Concurrent).await,
+            vec![3, 2, 1]
+        );
+    });
+}
+
+#[test]
+fn registering_while_dispatch_waits_on_a_full_channel() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = builder(&listener).with_channel_capacity(1).build().unwrap();
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+
+        connection.on_async(
+            "Tick",
+            HandlerExecution::Sequential,
+            move |n: u32, _: Value| {
+                let sender = sender.clone();
+
+                async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    sender.unbounded_send(n).unwrap();
+                }
+            },
+        );
+        let echoes = connection.on_stream::<(u32, Value)>("Tick");
+
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        let frames: String = (0..200)
+            .map(|n| {
+                let invocation = json!({ "type": 1, "target": "Tick", "arguments": [n, null] });
+                format!("{}\x1E", invocation)
+            })
+            .collect();
+        peer.send_text(&frames).unwrap();
+
+        let traffic = async {
+            futures::join!(
+                receiver.take(200).collect::<Vec<_>>(),
+                echoes
+                    .map(|echo| echo.unwrap().0)
+                    .take(200)
+                    .collect::<Vec<_>>()
+            )
+        };
+
+        let meanwhile = async {
+            // Dispatch is blocked on the handler's full channel by now.
+            tokio::time::sleep(Duration::from_millis(15)).await;
+            connection.on("Other", |_: Value, _: Value| {});
+            drop(connection.on_stream::<(u32, Value)>("Tick"));
+
+            let server = async {
+                let invocation = receive_message(&mut peer).await;
+                send_completion(&peer, &invocation, json!({ "result": "ok" }));
+            };
+
+            futures::join!(
+                connection.send_invocation("Ping".to_owned(), vec![]),
+                server
+            )
+            .0
+        };
+
+        let ((handled, echoed), result) = futures::join!(traffic, meanwhile);
+
+        assert_eq!(result, Ok(json!("ok")));
+        assert_eq!(handled, (0..200).collect::<Vec<_>>());
+        assert_eq!(echoed, (0..200).collect::<Vec<_>>());
+    });
+}
+
+/// Sends five invocations of `Tick` to a stream that isn't read until they've all been
+/// dispatched, returning what it yields and the connection.
+async fn overflow(policy: OverflowPolicy) -> (Vec<u32>, SignalRConnection) {
+    let mut listener = MemoryListener::new();
+    let connection = connection(&listener);
+    connection
+        .set_subscription_options(
+            "Tick",
+            SubscriptionOptions {
+                capacity: 2,
+                overflow_policy: policy,
+            },
+        )
+        .unwrap();
+    let ticks = connection.on_stream::<(u32,)>("Tick");
+
+    let (_, peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+    for n in 0..5 {
+        send_invocation(&peer, json!({ "target": "Tick", "arguments": [n] }));
+    }
+    tokio::time::sleep(Duration::from_millis(1)).await;
+
+    connection.stop(false).await.unwrap();
+    let received = ticks
+        .filter_map(|tick| async move { tick.ok() })
+        .map(|(n,)| n)
+        .collect()
+        .await;
+
+    (received, connection)
+}
+
+#[test]
+fn drop_oldest_keeps_the_latest_invocations() {
+    run(async {
+        let (received, connection) = overflow(OverflowPolicy::DropOldest).await;
+
+        assert_eq!(received, vec![3, 4]);
+        assert_eq!(connection.dropped_messages()["Tick"], 3);
+    });
+}
+
+#[test]
+fn drop_newest_keeps_the_earliest_invocations() {
+    run(async {
+        let (received, connection) = overflow(OverflowPolicy::DropNewest).await;
+
+        assert_eq!(received, vec![0, 1]);
+        assert_eq!(connection.dropped_messages()["Tick"], 3);
+    });
+}
+
+#[test]
+fn overflowing_with_the_disconnect_policy_closes_the_connection() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = builder(&listener)
+            .with_channel_capacity(1)
+            .with_overflow_policy(OverflowPolicy::Disconnect)
+            .build()
+            .unwrap();
+        let mut state_changes = connection.state_changes();
+        let _ticks = connection.on_stream::<(u32,)>("Tick");
+
+        let (_, peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+        for n in 0..2 {
+            send_invocation(&peer, json!({ "target": "Tick", "arguments": [n] }));
+        }
+
+        let last = loop {
+            let change = state_changes.next().await.unwrap();
+
+            if change.current == ConnectionState::Disconnected {
+                break change;
+            }
+        };
+
+        assert_eq!(
+            last.error,
+            Some(SignalRError::Overflow {
+                target: "Tick".to_owned()
+            })
+        );
+    });
+}
+
+#[test]
+fn events_after_an_overflow_are_not_dispatched() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let logs = Rc::new(RefCell::new(Vec::new()));
+        let recorded = logs.clone();
+        let connection = builder(&listener)
+            .with_channel_capacity(1)
+            .with_overflow_policy(OverflowPolicy::Disconnect)
+            .with_logger(move |_: LogLevel, message: &str| {
+                recorded.borrow_mut().push(message.to_owned())
+            })
+            .build()
+            .unwrap();
+        let _ticks = connection.on_stream::<(u32,)>("Tick");
+
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+        let invocation = connection.invoke("Send".to_owned(), vec![]).unwrap();
+        let sent = receive_message(&mut peer).await;
+
+        // One frame, so the overflow and what follows it are read together.
+        let tick = json!({ "type": 1, "target": "Tick", "arguments": [0] });
+        let completion = json!({ "type": 3, "invocationId": sent["invocationId"], "result": 1 });
+        let unhandled =
+            json!({ "type": 1, "invocationId": "9", "target": "Other", "arguments": [] });
+        peer.send_text(&format!(
+            "{}\x1E{}\x1E{}\x1E{}\x1E",
+            tick, tick, completion, unhandled
+        ))
+        .unwrap();
+
+        assert!(matches!(
+            invocation.result().await,
+            Err(SignalRError::ConnectionClosed { .. })
+        ));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(!logs
+            .borrow()
+            .iter()
+            .any(|m| m.contains("subscriber") || m.contains("Dropping")));
+    });
+}
+
+/// Sends invocations `1`, `2` and `3` asking for a result to a slow handler with room for one
+/// more invocation.
+async fn overflow_handler(
+    listener: &mut MemoryListener,
+    policy: OverflowPolicy,
+) -> (SignalRConnection, MemoryPeer) {
+    let connection = connection(listener);
+    connection
+        .set_subscription_options(
+            "Work",
+            SubscriptionOptions {
+                capacity: 1,
+                overflow_policy: policy,
+            },
+        )
+        .unwrap();
+    connection.on_async(
+        "Work",
+        HandlerExecution::Sequential,
+        |id: u64, delay: u64| async move {
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            id
+        },
+    );
+
+    let (_, peer) = futures::join!(connection.connect(), accept(listener, "{}\x1E"));
+    for id in 1..=3 {
+        send_invocation(
+            &peer,
+            json!({ "invocationId": id.to_string(), "target": "Work", "arguments": [id, 5] }),
+        );
+        // Lets the handler take the first invocation before the rest arrive.
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    (connection, peer)
+}
+
+fn dropped(id: &str) -> Value {
+    json!({ "type": 3, "invocationId": id, "error": "Client dropped the invocation." })
+}
+
+#[test]
+fn invocations_dropped_as_newest_are_answered_with_an_error() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (_connection, mut peer) =
+            overflow_handler(&mut listener, OverflowPolicy::DropNewest).await;
+
+        assert_eq!(receive_message(&mut peer).await, dropped("3"));
+        assert_eq!(receive_message(&mut peer).await["result"], 1);
+        assert_eq!(receive_message(&mut peer).await["result"], 2);
+    });
+}
+
+#[test]
+fn invocations_dropped_as_oldest_are_answered_with_an_error() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (_connection, mut peer) =
+            overflow_handler(&mut listener, OverflowPolicy::DropOldest).await;
+
+        assert_eq!(receive_message(&mut peer).await, dropped("2"));
+        assert_eq!(receive_message(&mut peer).await["result"], 1);
+        assert_eq!(receive_message(&mut peer).await["result"], 3);
+    });
+}
+
+#[test]
+fn invocations_overflowing_a_disconnecting_queue_are_answered_before_closing() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (_connection, mut peer) =
+            overflow_handler(&mut listener, OverflowPolicy::Disconnect).await;
+
+        assert_eq!(receive_message(&mut peer).await, dropped("3"));
+        assert!(matches!(
+            peer.receive().await,
+            Some(TransportEvent::Closed(_))
+        ));
+    });
+}
+
+#[test]
+fn losing_the_connection_fails_pending_invocations_and_streams() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+        let mut messages = connection.on_stream::<(String,)>("ReceiveMessage");
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        let server = async {
+            receive_message(&mut peer).await;
+            peer.close(1011, "Server error");
+        };
+        let (result, ()) =
+            futures::join!(connection.send_invocation("Add".to_owned(), vec![]), server);
+
+        let expected = SignalRError::ConnectionClosed {
+            reason: Some("WebSocket closed with code 1011 (Server error)".to_owned()),
+        };
+        assert_eq!(result, Err(expected.clone()));
+        assert_eq!(messages.next().await, Some(Err(expected)));
+        assert_eq!(messages.next().await, None);
+    });
+}
+
+#[test]
+fn transports_ending_without_a_close_event_fail_pending_invocations() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connector = listener.connector();
+        let connection = builder(&listener)
+            .with_transport(move || EndingTransport(connector()))
+            .build()
+            .unwrap();
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        let server = async {
+            receive_message(&mut peer).await;
+            peer.send_text("end").unwrap();
+        };
+        let (result, ()) =
+            futures::join!(connection.send_invocation("Add".to_owned(), vec![]), server);
+
+        assert!(matches!(
+            result,
+            Err(SignalRError::ConnectionClosed { reason: Some(_) })
+        ));
+        assert_eq!(connection.state(), ConnectionState::Disconnected);
+    });
+}
+
+#[test]
+fn invocations_made_while_reconnecting_are_sent_in_order_once_connected() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = builder(&listener)
+            .with_automatic_reconnect()
+            .with_offline_queue(10, Duration::from_secs(30))
+            .build()
+            .unwrap();
+        let mut state_changes = connection.state_changes();
+        let (_, peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        peer.close(1011, "Server error");
+        while state_changes.next().await.unwrap().current != ConnectionState::Reconnecting {}
+
+        let first = connection.invoke("First".to_owned(), vec![]).unwrap();
+        let second = connection.invoke("Second".to_owned(), vec![]).unwrap();
+        assert!(first.is_queued());
+        assert!(second.is_queued());
+
+        let mut peer = accept(&mut listener, "{}\x1E").await;
+        assert_eq!(first.sent().await, Ok(()));
+        assert_eq!(second.sent().await, Ok(()));
+
+        for target in ["First", "Second"] {
+            let invocation = receive_message(&mut peer).await;
+            assert_eq!(invocation["target"], target);
+
+            send_completion(&peer, &invocation, json!({ "result": target }));
+        }
+
+        assert_eq!(first.result().await, Ok(json!("First")));
+        assert_eq!(second.result().await, Ok(json!("Second")));
+    });
+}
+
+#[test]
+fn full_offline_queues_reject_invocations() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = builder(&listener)
+            .with_offline_queue(1, Duration::from_secs(30))
+            .build()
+            .unwrap();
+
+        let client = async {
+            let queued = connection.invoke("First".to_owned(), vec![]).unwrap();
+            let rejected = connection.invoke("Second".to_owned(), vec![]);
+
+            assert!(queued.is_queued());
+            assert!(matches!(rejected, Err(SignalRError::QueueFull(1))));
+        };
+        let (result, (), mut peer) = futures::join!(
+            connection.connect(),
+            client,
+            accept(&mut listener, "{}\x1E")
+        );
+        assert_eq!(result, Ok(()));
+
+        assert_eq!(receive_message(&mut peer).await["target"], "First");
+    });
+}
+
+#[test]
+fn queued_invocations_expire() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = builder(&listener)
+            .with_offline_queue(10, Duration::from_secs(1))
+            .build()
+            .unwrap();
+
+        let client = async {
+            let invocation = connection.invoke("Add".to_owned(), vec![]).unwrap();
+            let expired = SignalRError::QueueExpired(Duration::from_secs(1));
+
+            assert_eq!(invocation.sent().await, Err(expired.clone()));
+            assert_eq!(invocation.result().await, Err(expired));
+
+            accept(&mut listener, "{}\x1E").await
+        };
+        let (result, _peer) = futures::join!(connection.connect(), client);
+
+        assert_eq!(result, Ok(()));
+    });
+}
+
+/// Counts the sleeps that are neither finished nor dropped.
+struct SleepCountingRuntime {
+    runtime: TestRuntime,
+    sleeping: Rc<Cell<usize>>,
+}
+
+struct Sleeping(Rc<Cell<usize>>);
+
+impl Drop for Sleeping {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+impl Runtime for SleepCountingRuntime {
+    fn spawn_local(&self, future: LocalBoxFuture<'static, ()>) {
+        self.runtime.spawn_local(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        self.sleeping.set(self.sleeping.get() + 1);
+        let sleeping = Sleeping(self.sleeping.clone());
+        let sleep = self.runtime.sleep(duration);
+
+        async move {
+            let _sleeping = sleeping;
+            sleep.await
+        }
+        .boxed_local()
+    }
+
+    fn now(&self) -> Duration {
+        self.runtime.now()
+    }
+}
+
+#[test]
+fn sent_invocations_stop_waiting_to_expire() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let sleeping = Rc::new(Cell::new(0));
+        let connection = builder(&listener)
+            .with_runtime(SleepCountingRuntime {
+                runtime: TestRuntime {
+                    start: tokio::time::Instant::now(),
+                },
+                sleeping: sleeping.clone(),
+            })
+            .with_offline_queue(10, Duration::from_secs(30))
+            .build()
+            .unwrap();
+
+        let client = async {
+            let invocations: Vec<_> = (0..3)
+                .map(|_| connection.invoke("Add".to_owned(), vec![]).unwrap())
+                .collect();
+
+            for invocation in &invocations {
+                assert_eq!(invocation.sent().await, Ok(()));
+            }
+        };
+        let (result, (), _peer) = futures::join!(
+            connection.connect(),
+            client,
+            accept(&mut listener, "{}\x1E")
+        );
+        assert_eq!(result, Ok(()));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        // Only the reader's, waiting for the next keep-alive.
+        assert_eq!(sleeping.get(), 1);
+    });
+}
+
+/// Connects with automatic reconnects and `retries` invocation retries.
+async fn retrying_connection(
+    listener: &mut MemoryListener,
+    retries: u32,
+) -> (SignalRConnection, MemoryPeer) {
+    let connection = builder(listener)
+        .with_automatic_reconnect()
+        .with_invocation_retries(retries)
+        .with_client_id("client")
+        .build()
+        .unwrap();
+    let (result, peer) = futures::join!(connection.connect(), accept(listener, "{}\x1E"));
+    assert_eq!(result, Ok(()));
+
+    (connection, peer)
+}
+
+#[test]
+fn idempotent_invocations_are_retried_with_the_same_key_after_reconnecting() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (connection, mut peer) = retrying_connection(&mut listener, 1).await;
+
+        let idempotent = connection
+            .invoke_idempotent("Send".to_owned(), vec![json!("hello")])
+            .unwrap();
+        let other = connection.invoke("Other".to_owned(), vec![]).unwrap();
+
+        let first = receive_message(&mut peer).await;
+        assert_eq!(first["headers"], json!({ "Idempotency-Key": "client:1" }));
+        assert_eq!(receive_message(&mut peer).await.get("headers"), None);
+        peer.close(1011, "Server error");
+
+        assert!(matches!(
+            other.result().await,
+            Err(SignalRError::ConnectionClosed { reason: Some(_) })
+        ));
+
+        let mut peer = accept(&mut listener, "{}\x1E").await;
+        let retry = receive_message(&mut peer).await;
+        assert_eq!(retry["target"], "Send");
+        assert_eq!(retry["arguments"], json!(["hello"]));
+        assert_eq!(retry["headers"], first["headers"]);
+
+        send_completion(&peer, &retry, json!({ "result": "delivered" }));
+
+        assert_eq!(idempotent.result().await, Ok(json!("delivered")));
+    });
+}
+
+#[test]
+fn dropping_the_connection_ends_the_spans_of_pending_retries() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let spans = Rc::new(RefCell::new(Vec::new()));
+        let connection = builder(&listener)
+            .with_automatic_reconnect()
+            .with_invocation_retries(1)
+            .with_trace_provider(RecordingProvider {
+                context: TraceContext::new_root(),
+                spans: spans.clone(),
+            })
+            .build()
+            .unwrap();
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        let invocation = connection
+            .invoke_idempotent("Send".to_owned(), vec![])
+            .unwrap();
+        receive_message(&mut peer).await;
+        drop(connection);
+
+        assert_eq!(
+            invocation.result().await,
+            Err(SignalRError::ConnectionClosed { reason: None })
+        );
+        assert_eq!(spans.borrow().len(), 1);
+        assert!(spans.borrow()[0].error.is_some());
+    });
+}
+
+#[test]
+fn idempotent_invocations_fail_once_their_retries_are_used_up() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (connection, mut peer) = retrying_connection(&mut listener, 1).await;
+        let invocation = connection
+            .invoke_idempotent("Send".to_owned(), vec![])
+            .unwrap();
+
+        receive_message(&mut peer).await;
+        peer.close(1011, "Server error");
+
+        let mut peer = accept(&mut listener, "{}\x1E").await;
+        receive_message(&mut peer).await;
+        peer.close(1011, "Server error");
+
+        assert!(matches!(
+            invocation.result().await,
+            Err(SignalRError::ConnectionClosed { reason: Some(_) })
+        ));
+    });
+}
+
+fn tenant_headers() -> HashMap<String, String> {
+    HashMap::from([("tenant".to_owned(), "contoso".to_owned())])
+}
+
+#[test]
+fn invocation_headers_take_precedence_over_default_headers() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = builder(&listener)
+            .with_message_headers(tenant_headers)
+            .build()
+            .unwrap();
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        let options = InvocationOptions {
+            headers: HashMap::from([
+                ("tenant".to_owned(), "fabrikam".to_owned()),
+                ("correlation".to_owned(), "c1".to_owned()),
+            ]),
+            ..InvocationOptions::default()
+        };
+        let invocation = connection
+            .invoke_with("Add".to_owned(), vec![], options)
+            .unwrap();
+
+        let message = receive_message(&mut peer).await;
+        assert_eq!(
+            message["headers"],
+            json!({ "tenant": "fabrikam", "correlation": "c1" })
+        );
+
+        send_completion(
+            &peer,
+            &message,
+            json!({ "result": 3, "headers": { "correlation": "c1" } }),
+        );
+
+        let completion = invocation.completion().await.unwrap();
+        assert_eq!(completion.result, json!(3));
+        assert_eq!(completion.headers["correlation"], "c1");
+    });
+}
+
+#[test]
+fn handlers_see_invocation_headers_and_completions_get_default_headers() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = builder(&listener)
+            .with_message_headers(tenant_headers)
+            .build()
+            .unwrap();
+
+        connection.on_message("GetCorrelation", |message: InvocationMessage| {
+            message
+                .headers
+                .get("correlation")
+                .cloned()
+                .unwrap_or_default()
+        });
+
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        send_invocation(
+            &peer,
+            json!({
+                "invocationId": "7",
+                "target": "GetCorrelation",
+                "arguments": [],
+                "headers": { "correlation": "c2" },
+            }),
+        );
+
+        assert_eq!(
+            receive_message(&mut peer).await,
+            json!({
+                "type": 3,
+                "invocationId": "7",
+                "result": "c2",
+                "headers": { "tenant": "contoso" },
+            })
+        );
+    });
+}
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+/// Puts invocations in a fixed context and records their spans.
+struct RecordingProvider {
+    context: TraceContext,
+    spans: Rc<RefCell<Vec<InvocationSpan>>>,
+}
+
+impl TraceProvider for RecordingProvider {
+    fn current_context(&self) -> Option<TraceContext> {
+        Some(self.context.clone())
+    }
+
+    fn on_span_end(&self, span: InvocationSpan) {
+        self.spans.borrow_mut().push(span);
+    }
+}
+
+#[test]
+fn invocations_propagate_a_child_span_and_report_its_timing() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let parent = TraceContext::parse(TRACEPARENT, Some("congo=t61rcWkgMzE")).unwrap();
+        let spans = Rc::new(RefCell::new(Vec::new()));
+        let connection = builder(&listener)
+            .with_trace_provider(RecordingProvider {
+                context: parent.clone(),
+                spans: spans.clone(),
+            })
+            .build()
+            .unwrap();
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        let invocation = connection.invoke("Add".to_owned(), vec![]).unwrap();
+
+        let message = receive_message(&mut peer).await;
+        let sent = TraceContext::parse(
+            message["headers"]["traceparent"].as_str().unwrap(),
+            message["headers"]["tracestate"].as_str(),
+        )
+        .unwrap();
+        assert_eq!(sent.trace_id, parent.trace_id);
+        assert_ne!(sent.span_id, parent.span_id);
+        assert_eq!(sent.trace_state, parent.trace_state);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        send_completion(&peer, &message, json!({ "error": "Overflow" }));
+        assert!(invocation.result().await.is_err());
+
+        let spans = spans.borrow();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].target, "Add");
+        assert_eq!(spans[0].context, sent);
+        assert_eq!(spans[0].parent_span_id.as_ref(), Some(&parent.span_id));
+        assert_eq!(spans[0].duration, Duration::from_secs(2));
+        assert_eq!(spans[0].error.as_deref(), Some("Overflow"));
+    });
+}
+
+/// Records the spans of invocations made with `builder`'s connection.
+fn traced(builder: HubConnectionBuilder) -> (SignalRConnection, Rc<RefCell<Vec<InvocationSpan>>>) {
+    let spans = Rc::new(RefCell::new(Vec::new()));
+    let connection = builder
+        .with_trace_provider(RecordingProvider {
+            context: TraceContext::new_root(),
+            spans: spans.clone(),
+        })
+        .build()
+        .unwrap();
+
+    (connection, spans)
+}
+
+#[test]
+fn invocations_rejected_while_disconnected_end_their_span() {
+    run(async {
+        let listener = MemoryListener::new();
+        let (connection, spans) = traced(builder(&listener));
+
+        let error = match connection.invoke("Add".to_owned(), vec![]) {
+            Err(error) => error,
+            Ok(_) => panic!("Invoked while disconnected"),
+        };
+
+        let spans = spans.borrow();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].target, "Add");
+        assert_eq!(spans[0].error, Some(error.to_string()));
+    });
+}
+
+#[test]
+fn invocations_rejected_by_a_full_offline_queue_end_their_span() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (connection, spans) =
+            traced(builder(&listener).with_offline_queue(1, Duration::from_secs(30)));
+
+        let client = async {
+            let _queued = connection.invoke("First".to_owned(), vec![]).unwrap();
+            let rejected = connection.invoke("Second".to_owned(), vec![]);
+            assert!(matches!(rejected, Err(SignalRError::QueueFull(1))));
+
+            let spans = spans.borrow();
+            assert_eq!(spans.len(), 1);
+            assert_eq!(spans[0].target, "Second");
+            assert_eq!(spans[0].error, Some(SignalRError::QueueFull(1).to_string()));
+        };
+        let (result, (), _peer) = futures::join!(
+            connection.connect(),
+            client,
+            accept(&mut listener, "{}\x1E")
+        );
+        assert_eq!(result, Ok(()));
+    });
+}
+
+#[test]
+fn handlers_can_read_the_propagated_trace_context() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let connection = connection(&listener);
+
+        connection.on_message("GetTraceId", |message: InvocationMessage| {
+            message
+                .trace_context()
+                .map(|context| context.trace_id)
+                .unwrap_or_default()
+        });
+
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        send_invocation(
+            &peer,
+            json!({
+                "invocationId": "7",
+                "target": "GetTraceId",
+                "arguments": [],
+                "headers": { "traceparent": TRACEPARENT },
+            }),
+        );
+
+        let completion = receive_message(&mut peer).await;
+        assert_eq!(completion["result"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    });
+}
+
+type LogRecords = Rc<RefCell<Vec<(LogLevel, String)>>>;
+
+fn recording_builder(
+    listener: &MemoryListener,
+    level: LogLevel,
+) -> (HubConnectionBuilder, LogRecords) {
+    let records = LogRecords::default();
+    let recorded = records.clone();
+    let builder = builder(listener).with_log_level(level).with_logger(
+        move |level: LogLevel, message: &str| {
+            recorded.borrow_mut().push((level, message.to_owned()))
+        },
+    );
+
+    (builder, records)
+}
+
+#[test]
+fn connections_log_through_their_logger_without_message_contents() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (builder, records) = recording_builder(&listener, LogLevel::Trace);
+        let connection = builder.build().unwrap();
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        let invocation = connection
+            .invoke("Login".to_owned(), vec![json!("hunter2")])
+            .unwrap();
+        let message = receive_message(&mut peer).await;
+        send_completion(&peer, &message, json!({ "result": "token-secret" }));
+        invocation.result().await.unwrap();
+
+        let records = records.borrow();
+        assert!(records.contains(&(LogLevel::Debug, "Handshake completed".to_owned())));
+        assert!(records.contains(&(
+            LogLevel::Info,
+            "Successfully established connection".to_owned()
+        )));
+        assert!(records.iter().any(|(level, _)| *level == LogLevel::Trace));
+        assert!(records
+            .iter()
+            .all(|(_, message)| !message.contains("hunter2") && !message.contains("token-secret")));
+    });
+}
+
+#[test]
+fn messages_below_the_log_level_are_dropped() {
+    run(async {
+        let mut listener = MemoryListener::new();
+        let (builder, records) = recording_builder(&listener, LogLevel::Warn);
+        let connection = builder.build().unwrap();
+        let (_, mut peer) = futures::join!(connection.connect(), accept(&mut listener, "{}\x1E"));
+
+        send_invocation(
+            &peer,
+            json!({ "invocationId": "1", "target": "Unknown", "arguments": [] }),
+        );
+        // Answered with an error once the invocation has been dispatched.
+        receive_message(&mut peer).await;
+
+        assert_eq!(
+            *records.borrow(),
+            [(
+                LogLevel::Warn,
+                "No handler registered for invocation target Unknown".to_owned()
+            )]
+        );
+    });
+}
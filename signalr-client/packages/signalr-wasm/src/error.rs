@@ -0,0 +1,202 @@
+use std::fmt;
+use std::time::Duration;
+use wasm_bindgen::JsValue;
+use web_sys::CloseEvent;
+
+use crate::state::ConnectionState;
+
+/// The details of a WebSocket close event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseInfo {
+    pub code: u16,
+    pub reason: String,
+    pub was_clean: bool,
+}
+
+impl From<&CloseEvent> for CloseInfo {
+    fn from(event: &CloseEvent) -> Self {
+        Self {
+            code: event.code(),
+            reason: event.reason(),
+            was_clean: event.was_clean(),
+        }
+    }
+}
+
+impl fmt::Display for CloseInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WebSocket closed with code {}", self.code)?;
+
+        if !self.reason.is_empty() {
+            write!(f, " ({})", self.reason)?;
+        }
+
+        if !self.was_clean {
+            f.write_str(" uncleanly")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignalRError {
+    /// The operation isn't allowed while the connection is in `state`.
+    InvalidState {
+        operation: &'static str,
+        state: ConnectionState,
+    },
+    /// The connection ended before the operation could complete.
+    ConnectionClosed {
+        reason: Option<String>,
+    },
+    /// The socket was closed, either while connecting or after.
+    SocketClosed(CloseInfo),
+    /// The server didn't complete the handshake within the configured timeout.
+    HandshakeTimeout(Duration),
+    /// Nothing was received from the server within the configured server timeout.
+    ServerTimeout(Duration),
+    /// `connect` was cancelled before it finished.
+    Aborted,
+    /// The server kept rejecting the access token, even after refreshing it.
+    Unauthorized(String),
+    /// Invocations of `target` arrived faster than they were handled, and its
+    /// [`OverflowPolicy`](crate::OverflowPolicy) is to disconnect.
+    Overflow {
+        target: String,
+    },
+    /// The offline queue already held this many invocations.
+    QueueFull(usize),
+    /// The invocation waited in the offline queue for this long without the connection
+    /// being reestablished.
+    QueueExpired(Duration),
+    Other(String),
+}
+
+impl fmt::Display for SignalRError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignalRError::InvalidState { operation, state } => write!(
+                f,
+                "Cannot {} while the connection is in the {} state",
+                operation, state
+            ),
+            SignalRError::ConnectionClosed { reason: None } => f.write_str("Connection closed"),
+            SignalRError::ConnectionClosed {
+                reason: Some(reason),
+            } => write!(f, "Connection closed: {}", reason),
+            SignalRError::SocketClosed(info) => info.fmt(f),
+            SignalRError::HandshakeTimeout(timeout) => {
+                write!(f, "Handshake did not complete within {:?}", timeout)
+            }
+            SignalRError::ServerTimeout(timeout) => {
+                write!(f, "Received nothing from the server within {:?}", timeout)
+            }
+            SignalRError::Aborted => f.write_str("Connection attempt was aborted"),
+            SignalRError::Unauthorized(reason) => write!(f, "Unauthorized: {}", reason),
+            SignalRError::Overflow { target } => {
+                write!(
+                    f,
+                    "Invocations of `{}` were not handled fast enough",
+                    target
+                )
+            }
+            SignalRError::QueueFull(size) => {
+                write!(f, "The offline queue is full ({} invocations)", size)
+            }
+            SignalRError::QueueExpired(expiry) => {
+                write!(f, "Invocation was not sent within {:?}", expiry)
+            }
+            SignalRError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl SignalRError {
+    /// Whether the error looks like the server rejecting the access token, meaning a fresh
+    /// token might succeed where the current one didn't.
+    ///
+    /// Servers have no standard way to say so over an open WebSocket, so besides
+    /// [`SignalRError::Unauthorized`] this is a heuristic: a policy violation close (1008) or
+    /// a close message from the hub whose reason says `401`, `Unauthorized`,
+    /// `Unauthenticated` or `token expired` as separate words. Other policy violations, such
+    /// as an oversized message, are not auth failures.
+    pub fn is_auth_failure(&self) -> bool {
+        match self {
+            SignalRError::Unauthorized(_) => true,
+            SignalRError::SocketClosed(info) => {
+                info.code == POLICY_VIOLATION && mentions_auth(&info.reason)
+            }
+            SignalRError::ConnectionClosed {
+                reason: Some(reason),
+            } => mentions_auth(reason),
+            _ => false,
+        }
+    }
+}
+
+/// The WebSocket close code servers commonly use when rejecting credentials.
+const POLICY_VIOLATION: u16 = 1008;
+
+fn mentions_auth(reason: &str) -> bool {
+    let reason = reason.to_ascii_lowercase();
+    let words: Vec<&str> = reason
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    words
+        .iter()
+        .any(|word| matches!(*word, "401" | "unauthorized" | "unauthenticated"))
+        || words.windows(2).any(|pair| pair == ["token", "expired"])
+}
+
+impl std::error::Error for SignalRError {}
+
+impl From<String> for SignalRError {
+    fn from(message: String) -> Self {
+        SignalRError::Other(message)
+    }
+}
+
+impl From<SignalRError> for JsValue {
+    fn from(error: SignalRError) -> Self {
+        JsValue::from(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closed(code: u16, reason: &str) -> SignalRError {
+        SignalRError::SocketClosed(CloseInfo {
+            code,
+            reason: reason.to_owned(),
+            was_clean: true,
+        })
+    }
+
+    #[test]
+    fn auth_failures_need_a_policy_violation_that_mentions_auth() {
+        assert!(closed(1008, "Unauthorized").is_auth_failure());
+        assert!(closed(1008, "Access token expired").is_auth_failure());
+        assert!(closed(1008, "HTTP 401").is_auth_failure());
+
+        assert!(!closed(1008, "Message too big").is_auth_failure());
+        assert!(!closed(1008, "").is_auth_failure());
+        assert!(!closed(1011, "Unauthorized").is_auth_failure());
+        assert!(!closed(1008, "Room 4011 is full").is_auth_failure());
+    }
+
+    #[test]
+    fn hub_close_messages_are_auth_failures_when_they_say_so() {
+        let closed = |reason: &str| SignalRError::ConnectionClosed {
+            reason: Some(reason.to_owned()),
+        };
+
+        assert!(closed("Connection closed with an error. Unauthorized").is_auth_failure());
+        assert!(!closed("Server is shutting down").is_auth_failure());
+        assert!(!closed("Authentication service is degraded").is_auth_failure());
+    }
+}
@@ -0,0 +1,379 @@
+use js_sys::{Function, Object, Promise, Reflect};
+use std::cell::{Cell, OnceCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+use crate::builder::HubConnectionBuilder;
+use crate::js::connection::JsHubConnection;
+use crate::js::{error_message, headers_from_js};
+use crate::log::{Log, LogLevel};
+use crate::reconnect::DefaultReconnectPolicy;
+use crate::trace::{InvocationSpan, TraceContext, TraceProvider};
+
+#[wasm_bindgen(js_name = HubConnectionBuilder)]
+pub struct JsHubConnectionBuilder {
+    builder: HubConnectionBuilder,
+    log: ConnectionLog,
+}
+
+/// The built connection's [`Log`], for callbacks configured before the connection exists.
+type ConnectionLog = Rc<OnceCell<Log>>;
+
+#[wasm_bindgen(js_class = HubConnectionBuilder)]
+impl JsHubConnectionBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            builder: HubConnectionBuilder::new(),
+            log: ConnectionLog::default(),
+        }
+    }
+
+    pub fn with_url(self, url: &str) -> Self {
+        Self {
+            builder: self.builder.with_url(url),
+            ..self
+        }
+    }
+
+    pub fn with_protocol(self, protocol: &str) -> Result<Self, JsValue> {
+        Ok(Self {
+            builder: self.builder.with_protocol(protocol.parse()?),
+            ..self
+        })
+    }
+
+    pub fn with_transports(self, transports: Vec<String>) -> Result<Self, JsValue> {
+        let transports = transports
+            .iter()
+            .map(|t| t.parse())
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self {
+            builder: self.builder.with_transports(&transports),
+            ..self
+        })
+    }
+
+    /// Creates WebSockets with `constructor` instead of the global `WebSocket`, such as the
+    /// `ws` package's in Node. Headers set with `with_header` are passed to it.
+    pub fn with_websocket_constructor(self, constructor: Function) -> Self {
+        Self {
+            builder: self.builder.with_websocket_constructor(constructor),
+            ..self
+        }
+    }
+
+    /// Creates WebSockets by calling `factory(url, headers)`, which may return a fake or
+    /// wrapped socket for tests or instrumentation.
+    pub fn with_websocket_factory(self, factory: Function) -> Self {
+        Self {
+            builder: self.builder.with_websocket_factory(move |url, headers| {
+                let init = Object::new();
+                for (name, value) in headers {
+                    Reflect::set(&init, &name.into(), &value.into())
+                        .map_err(|e| format!("Failed to pass headers: {:?}", e))?;
+                }
+
+                let socket = factory
+                    .call2(&JsValue::NULL, &url.into(), &init)
+                    .map_err(|e| format!("WebSocket factory threw: {:?}", e))?;
+                if !socket.is_object() {
+                    return Err("WebSocket factory did not return an object".to_owned());
+                }
+
+                Ok(socket.unchecked_into())
+            }),
+            ..self
+        }
+    }
+
+    /// `factory` may return the token directly or a Promise resolving to it. It is passed
+    /// `true` when the previous token was rejected and must not be reused.
+    pub fn with_access_token_factory(self, factory: Function) -> Self {
+        Self {
+            builder: self
+                .builder
+                .with_access_token_factory(move |force_refresh| {
+                    let factory = factory.clone();
+
+                    async move {
+                        let token = factory
+                            .call1(&JsValue::null(), &JsValue::from(force_refresh))
+                            .map_err(|e| format!("Access token factory threw: {:?}", e))?;
+                        let token = JsFuture::from(Promise::resolve(&token))
+                            .await
+                            .map_err(|e| format!("Access token factory rejected: {:?}", e))?;
+
+                        token
+                            .as_string()
+                            .ok_or_else(|| "Access token factory must return a string".to_owned())
+                    }
+                }),
+            ..self
+        }
+    }
+
+    pub fn with_skip_negotiation(self, skip_negotiation: bool) -> Self {
+        Self {
+            builder: self.builder.with_skip_negotiation(skip_negotiation),
+            ..self
+        }
+    }
+
+    pub fn with_token_refresh_attempts(self, attempts: u32) -> Self {
+        Self {
+            builder: self.builder.with_token_refresh_attempts(attempts),
+            ..self
+        }
+    }
+
+    pub fn with_header(self, name: &str, value: &str) -> Self {
+        Self {
+            builder: self.builder.with_header(name, value),
+            ..self
+        }
+    }
+
+    /// Reconnects after each of `delays_ms` in turn, or with the default delays if omitted.
+    pub fn with_automatic_reconnect(self, delays_ms: Option<Vec<u32>>) -> Self {
+        let builder = match delays_ms {
+            Some(delays) => self
+                .builder
+                .with_reconnect_policy(DefaultReconnectPolicy::new(
+                    delays.into_iter().map(millis).collect(),
+                )),
+            None => self.builder.with_automatic_reconnect(),
+        };
+
+        Self { builder, ..self }
+    }
+
+    pub fn with_handshake_timeout(self, timeout_ms: u32) -> Self {
+        Self {
+            builder: self.builder.with_handshake_timeout(millis(timeout_ms)),
+            ..self
+        }
+    }
+
+    pub fn with_server_timeout(self, timeout_ms: u32) -> Self {
+        Self {
+            builder: self.builder.with_server_timeout(millis(timeout_ms)),
+            ..self
+        }
+    }
+
+    pub fn with_keep_alive_interval(self, interval_ms: u32) -> Self {
+        Self {
+            builder: self.builder.with_keep_alive_interval(millis(interval_ms)),
+            ..self
+        }
+    }
+
+    pub fn with_log_level(self, level: &str) -> Result<Self, JsValue> {
+        Ok(Self {
+            builder: self.builder.with_log_level(level.parse()?),
+            ..self
+        })
+    }
+
+    /// Calls `logger(level, message)` for each message the log level lets through instead of
+    /// writing to the console, with `level` one of `trace`, `debug`, `info`, `warn` or `error`.
+    /// Only the first time it throws is reported, on the console.
+    pub fn with_logger(self, logger: Function) -> Self {
+        let reported = Cell::new(false);
+
+        Self {
+            builder: self
+                .builder
+                .with_logger(move |level: LogLevel, message: &str| {
+                    let level = JsValue::from(level.as_str());
+                    if let Err(e) = logger.call2(&JsValue::null(), &level, &JsValue::from(message))
+                    {
+                        if !reported.replace(true) {
+                            console_error!("Logger threw: {}", error_message(&e));
+                        }
+                    }
+                }),
+            ..self
+        }
+    }
+
+    pub fn with_channel_capacity(self, capacity: usize) -> Self {
+        Self {
+            builder: self.builder.with_channel_capacity(capacity),
+            ..self
+        }
+    }
+
+    /// One of `Block`, `DropOldest`, `DropNewest` or `Disconnect`.
+    pub fn with_overflow_policy(self, policy: &str) -> Result<Self, JsValue> {
+        Ok(Self {
+            builder: self.builder.with_overflow_policy(policy.parse()?),
+            ..self
+        })
+    }
+
+    /// Holds up to `max_size` invocations made while connecting or reconnecting, each for at
+    /// most `expiry_ms`, and sends them once connected.
+    pub fn with_offline_queue(self, max_size: u32, expiry_ms: u32) -> Self {
+        Self {
+            builder: self
+                .builder
+                .with_offline_queue(max_size as usize, millis(expiry_ms)),
+            ..self
+        }
+    }
+
+    /// How many times idempotent invocations are sent again after reconnecting.
+    pub fn with_invocation_retries(self, retries: u32) -> Self {
+        Self {
+            builder: self.builder.with_invocation_retries(retries),
+            ..self
+        }
+    }
+
+    pub fn with_client_id(self, client_id: &str) -> Self {
+        Self {
+            builder: self.builder.with_client_id(client_id),
+            ..self
+        }
+    }
+
+    /// Calls `factory()` for every invocation and completion sent, adding the headers in the
+    /// object it returns. Headers set on the message itself take precedence.
+    pub fn with_message_headers(self, factory: Function) -> Self {
+        let log = self.log.clone();
+
+        Self {
+            builder: self.builder.with_message_headers(move || {
+                let headers = factory
+                    .call0(&JsValue::null())
+                    .and_then(|headers| headers_from_js(&headers));
+
+                headers.unwrap_or_else(|e| {
+                    report(
+                        &log,
+                        format_args!("Message headers factory failed: {}", error_message(&e)),
+                    );
+                    HashMap::new()
+                })
+            }),
+            ..self
+        }
+    }
+
+    /// Traces invocations. `current_context()` returns the active `{ traceparent, tracestate }`,
+    /// or `undefined` to start a new trace, and `on_span_end(span)` receives each invocation's
+    /// span with its `target`, `traceparent`, `parentSpanId`, `start` and `duration` in
+    /// milliseconds, and `error`.
+    pub fn with_trace_provider(
+        self,
+        current_context: Function,
+        on_span_end: Option<Function>,
+    ) -> Self {
+        Self {
+            builder: self.builder.with_trace_provider(JsTraceProvider {
+                current_context,
+                on_span_end,
+                log: self.log.clone(),
+            }),
+            ..self
+        }
+    }
+
+    pub fn build(self) -> Result<JsHubConnection, JsValue> {
+        let connection = self.builder.build()?;
+        let _ = self.log.set(connection.log().clone());
+
+        Ok(JsHubConnection::new(connection))
+    }
+}
+
+impl Default for JsHubConnectionBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn millis(ms: u32) -> Duration {
+    Duration::from_millis(ms.into())
+}
+
+/// Logs a failed callback as an error. Callbacks only run once the connection is built, so the
+/// console is just a fallback.
+fn report(log: &ConnectionLog, message: fmt::Arguments) {
+    match log.get() {
+        Some(log) => log_error!(log, "{}", message),
+        None => console_error!("{}", message),
+    }
+}
+
+struct JsTraceProvider {
+    current_context: Function,
+    on_span_end: Option<Function>,
+    log: ConnectionLog,
+}
+
+impl TraceProvider for JsTraceProvider {
+    fn current_context(&self) -> Option<TraceContext> {
+        let context = match self.current_context.call0(&JsValue::null()) {
+            Ok(context) if context.is_object() => context,
+            Ok(_) => return None,
+            Err(e) => {
+                report(
+                    &self.log,
+                    format_args!("Trace context provider threw: {}", error_message(&e)),
+                );
+                return None;
+            }
+        };
+        let field = |name: &str| {
+            Reflect::get(&context, &name.into())
+                .ok()
+                .and_then(|value| value.as_string())
+        };
+
+        let traceparent = field("traceparent")?;
+        let context = TraceContext::parse(&traceparent, field("tracestate").as_deref());
+        if context.is_none() {
+            report(
+                &self.log,
+                format_args!("Ignoring malformed traceparent `{}`", traceparent),
+            );
+        }
+
+        context
+    }
+
+    fn on_span_end(&self, span: InvocationSpan) {
+        let callback = match &self.on_span_end {
+            Some(callback) => callback,
+            None => return,
+        };
+
+        let object = Object::new();
+        let fields: [(&str, JsValue); 6] = [
+            ("target", span.target.into()),
+            ("traceparent", span.context.traceparent().into()),
+            ("parentSpanId", span.parent_span_id.into()),
+            ("start", (span.start.as_secs_f64() * 1000.0).into()),
+            ("duration", (span.duration.as_secs_f64() * 1000.0).into()),
+            ("error", span.error.into()),
+        ];
+        for (name, value) in &fields {
+            let _ = Reflect::set(&object, &(*name).into(), value);
+        }
+
+        if let Err(e) = callback.call1(&JsValue::null(), &object) {
+            report(
+                &self.log,
+                format_args!("Span callback threw: {}", error_message(&e)),
+            );
+        }
+    }
+}
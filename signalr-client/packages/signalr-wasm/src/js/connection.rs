@@ -0,0 +1,272 @@
+use futures::future::{select, Either};
+use futures::StreamExt;
+use js_sys::{Array, Function, Object, Promise, Reflect};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{future_to_promise, spawn_local, JsFuture};
+use web_sys::AbortSignal;
+
+use crate::builder::SubscriptionOptions;
+use crate::connection::{HandlerExecution, Invocation, InvocationOptions, SignalRConnection};
+use crate::error::SignalRError;
+use crate::js::{
+    aborted, error_message, headers_from_js, headers_to_js, value_from_js, value_to_js,
+};
+use crate::message::InvocationMessage;
+
+#[wasm_bindgen(js_name = HubConnection)]
+pub struct JsHubConnection {
+    connection: SignalRConnection,
+}
+
+impl JsHubConnection {
+    pub(super) fn new(connection: SignalRConnection) -> Self {
+        Self { connection }
+    }
+
+    /// Registers a handler calling `call`, which returns a value or a Promise of one.
+    fn register(
+        &self,
+        method_name: &str,
+        concurrent: Option<bool>,
+        call: impl Fn(InvocationMessage) -> Result<JsValue, String> + 'static,
+    ) {
+        let execution = if concurrent.unwrap_or(false) {
+            HandlerExecution::Concurrent
+        } else {
+            HandlerExecution::Sequential
+        };
+
+        self.connection
+            .on_message_async(method_name, execution, move |message| {
+                let returned = call(message);
+
+                async move {
+                    let returned = JsFuture::from(Promise::resolve(&returned?))
+                        .await
+                        .map_err(|e| format!("Handler rejected: {}", error_message(&e)))?;
+
+                    value_from_js(&returned)
+                        .map_err(|e| format!("Failed to convert handler result: {:?}", e))
+                }
+            });
+    }
+
+    /// Starts an invocation, reading `options` as `{ idempotent, headers }`.
+    fn invocation(
+        &self,
+        method_name: String,
+        args: Vec<JsValue>,
+        options: Option<Object>,
+    ) -> Result<Invocation, JsValue> {
+        let args = args
+            .iter()
+            .map(value_from_js)
+            .collect::<Result<Vec<_>, JsValue>>()?;
+        let options = match options {
+            Some(options) => InvocationOptions {
+                idempotent: Reflect::get(&options, &"idempotent".into())?.is_truthy(),
+                headers: headers_from_js(&Reflect::get(&options, &"headers".into())?)?,
+            },
+            None => InvocationOptions::default(),
+        };
+
+        Ok(self.connection.invoke_with(method_name, args, options)?)
+    }
+}
+
+fn handler_arguments(message: &InvocationMessage) -> Result<Array, String> {
+    message
+        .arguments
+        .iter()
+        .map(value_to_js)
+        .collect::<Result<Array, JsValue>>()
+        .map_err(|e| format!("Failed to convert handler arguments: {:?}", e))
+}
+
+#[wasm_bindgen(js_class = HubConnection)]
+impl JsHubConnection {
+    /// Connects to the hub. Aborting `signal` cancels the attempt and closes the socket.
+    pub async fn start(&self, signal: Option<AbortSignal>) -> Result<(), JsValue> {
+        let connect = self.connection.connect();
+
+        let log = self.connection.log();
+
+        let result = match signal {
+            Some(signal) => match select(Box::pin(connect), Box::pin(aborted(signal, log))).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Err(SignalRError::Aborted),
+            },
+            None => connect.await,
+        };
+
+        result.map_err(JsValue::from)
+    }
+
+    /// Closes the connection. Sends the server a Close message first unless
+    /// `send_close_message` is `false`.
+    pub async fn stop(&self, send_close_message: Option<bool>) -> Result<(), JsValue> {
+        self.connection
+            .stop(send_close_message.unwrap_or(true))
+            .await
+            .map_err(JsValue::from)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn state(&self) -> String {
+        self.connection.state().to_string()
+    }
+
+    /// Calls `callback` with the new and previous state names whenever the state changes,
+    /// plus an error message if a failure caused the change.
+    pub fn on_state_changed(&self, callback: Function) {
+        let mut changes = self.connection.state_changes();
+        let log = self.connection.log().clone();
+
+        spawn_local(async move {
+            while let Some(change) = changes.next().await {
+                let current = JsValue::from(change.current.as_str());
+                let previous = JsValue::from(change.previous.as_str());
+                let error = change
+                    .error
+                    .map_or(JsValue::undefined(), |e| JsValue::from(e.to_string()));
+
+                if let Err(e) = callback.call3(&JsValue::null(), &current, &previous, &error) {
+                    log_error!(log, "State change callback threw: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Calls `callback` with the invocation's arguments whenever the server invokes `method_name`.
+    /// If the server asks for a result, the callback's return value, or what its Promise resolves
+    /// to, is sent back. Throwing or rejecting sends the error instead.
+    ///
+    /// Each call waits for the previous one's Promise to settle, unless `concurrent` is `true`.
+    pub fn on(&self, method_name: &str, callback: Function, concurrent: Option<bool>) {
+        self.register(method_name, concurrent, move |message| {
+            let args = handler_arguments(&message)?;
+
+            callback
+                .apply(&JsValue::null(), &args)
+                .map_err(|e| format!("Handler threw: {}", error_message(&e)))
+        });
+    }
+
+    /// Like `on`, but calls `callback` with an array of the invocation's arguments and an
+    /// object of its headers.
+    pub fn on_message(&self, method_name: &str, callback: Function, concurrent: Option<bool>) {
+        self.register(method_name, concurrent, move |message| {
+            let args = handler_arguments(&message)?;
+            let headers = headers_to_js(&message.headers)
+                .map_err(|e| format!("Failed to convert handler headers: {:?}", e))?;
+
+            callback
+                .call2(&JsValue::null(), &args, &headers)
+                .map_err(|e| format!("Handler threw: {}", error_message(&e)))
+        });
+    }
+
+    /// Overrides the channel capacity and overflow policy for `method_name`'s handler.
+    pub fn set_subscription_options(
+        &self,
+        method_name: &str,
+        capacity: usize,
+        overflow_policy: &str,
+    ) -> Result<(), JsValue> {
+        let options = SubscriptionOptions {
+            capacity,
+            overflow_policy: overflow_policy.parse()?,
+        };
+
+        Ok(self
+            .connection
+            .set_subscription_options(method_name, options)?)
+    }
+
+    /// An object mapping each method name to how many of its invocations were dropped.
+    pub fn dropped_messages(&self) -> Result<Object, JsValue> {
+        let dropped = Object::new();
+
+        for (method_name, count) in self.connection.dropped_messages() {
+            Reflect::set(&dropped, &method_name.into(), &(count as f64).into())?;
+        }
+
+        Ok(dropped)
+    }
+
+    /// Identifies this connection in the idempotency keys of its invocations.
+    #[wasm_bindgen(getter)]
+    pub fn client_id(&self) -> String {
+        self.connection.client_id().to_owned()
+    }
+
+    /// Invokes `method_name` on the hub and resolves with its return value. `options` may set
+    /// `idempotent: true` for hub methods that are safe to run again after a reconnect, and
+    /// `headers` to send with the invocation.
+    pub async fn invoke(
+        &self,
+        method_name: String,
+        args: Vec<JsValue>,
+        options: Option<Object>,
+    ) -> Result<JsValue, JsValue> {
+        let result = self
+            .invocation(method_name, args, options)?
+            .result()
+            .await?;
+
+        value_to_js(&result)
+    }
+
+    /// Starts invoking `method_name` on the hub without waiting for it. While connecting or
+    /// reconnecting it is held in the offline queue, if one is configured.
+    /// Takes the same `options` as `invoke`.
+    pub fn start_invocation(
+        &self,
+        method_name: String,
+        args: Vec<JsValue>,
+        options: Option<Object>,
+    ) -> Result<JsInvocation, JsValue> {
+        let invocation = self.invocation(method_name, args, options)?;
+        let sent = invocation.sent();
+
+        Ok(JsInvocation {
+            queued: invocation.is_queued(),
+            sent: future_to_promise(async move {
+                sent.await?;
+                Ok(JsValue::undefined())
+            }),
+            result: future_to_promise(async move {
+                let result = invocation.result().await?;
+                value_to_js(&result)
+            }),
+        })
+    }
+}
+
+#[wasm_bindgen(js_name = Invocation)]
+pub struct JsInvocation {
+    queued: bool,
+    sent: Promise,
+    result: Promise,
+}
+
+#[wasm_bindgen(js_class = Invocation)]
+impl JsInvocation {
+    /// Whether the invocation is waiting in the offline queue rather than sent right away.
+    #[wasm_bindgen(getter)]
+    pub fn queued(&self) -> bool {
+        self.queued
+    }
+
+    /// Resolves once the invocation has been sent to the server.
+    #[wasm_bindgen(getter)]
+    pub fn sent(&self) -> Promise {
+        self.sent.clone()
+    }
+
+    /// Resolves with the hub method's return value.
+    #[wasm_bindgen(getter)]
+    pub fn result(&self) -> Promise {
+        self.result.clone()
+    }
+}
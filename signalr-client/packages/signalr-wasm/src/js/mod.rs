@@ -0,0 +1,103 @@
+//! Bindings exposing the connection and its builder to JavaScript.
+
+mod builder;
+mod connection;
+
+use futures::channel::oneshot;
+use js_sys::JSON;
+use serde_json::Value;
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+use web_sys::AbortSignal;
+
+use crate::log::Log;
+
+fn value_to_js(value: &Value) -> Result<JsValue, JsValue> {
+    let json = serde_json::to_string(value)
+        .map_err(|e| JsValue::from(format!("Failed to serialize value: {}", e)))?;
+
+    JSON::parse(&json)
+}
+
+fn value_from_js(value: &JsValue) -> Result<Value, JsValue> {
+    if value.is_undefined() {
+        return Ok(Value::Null);
+    }
+
+    let json: String = JSON::stringify(value)?.into();
+
+    serde_json::from_str(&json)
+        .map_err(|e| JsValue::from(format!("Failed to deserialize value: {}", e)))
+}
+
+/// Reads an object of string values as message headers. `undefined` and `null` are empty.
+fn headers_from_js(value: &JsValue) -> Result<HashMap<String, String>, JsValue> {
+    match value_from_js(value)? {
+        Value::Null => Ok(HashMap::new()),
+        Value::Object(headers) => headers
+            .into_iter()
+            .map(|(name, value)| match value {
+                Value::String(value) => Ok((name, value)),
+                other => Err(format!("Header `{}` must be a string, not {}", name, other).into()),
+            })
+            .collect(),
+        other => Err(format!("Headers must be an object, not {}", other).into()),
+    }
+}
+
+fn headers_to_js(headers: &HashMap<String, String>) -> Result<JsValue, JsValue> {
+    let headers = serde_json::to_value(headers)
+        .map_err(|e| JsValue::from(format!("Failed to serialize headers: {}", e)))?;
+
+    value_to_js(&headers)
+}
+
+/// The message of a thrown `Error`, or the thrown value itself if it's something else.
+fn error_message(error: &JsValue) -> String {
+    match error.dyn_ref::<js_sys::Error>() {
+        Some(error) => error.message().into(),
+        None => error.as_string().unwrap_or_else(|| format!("{:?}", error)),
+    }
+}
+
+/// Resolves once `signal` is aborted. The listener is removed if this future is dropped first.
+async fn aborted(signal: AbortSignal, log: &Log) {
+    if signal.aborted() {
+        return;
+    }
+
+    let (sender, receiver) = oneshot::channel::<()>();
+    let mut sender = Some(sender);
+    let on_abort = Closure::<dyn FnMut()>::new(move || {
+        if let Some(sender) = sender.take() {
+            let _ = sender.send(());
+        }
+    });
+
+    if let Err(e) =
+        signal.add_event_listener_with_callback("abort", on_abort.as_ref().unchecked_ref())
+    {
+        log_error!(log, "Failed to listen for abort: {:?}", e);
+        return futures::future::pending().await;
+    }
+
+    let _listener = AbortListener {
+        signal: &signal,
+        on_abort: &on_abort,
+    };
+
+    let _ = receiver.await;
+}
+
+struct AbortListener<'a> {
+    signal: &'a AbortSignal,
+    on_abort: &'a Closure<dyn FnMut()>,
+}
+
+impl Drop for AbortListener<'_> {
+    fn drop(&mut self) {
+        let _ = self
+            .signal
+            .remove_event_listener_with_callback("abort", self.on_abort.as_ref().unchecked_ref());
+    }
+}
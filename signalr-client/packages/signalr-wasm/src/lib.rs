@@ -3,13 +3,56 @@ use wasm_bindgen::prelude::wasm_bindgen;
 #[macro_use]
 mod log;
 
+#[cfg(all(feature = "native", target_arch = "wasm32"))]
+compile_error!("The `native` feature is for non-wasm targets");
+
+mod builder;
+#[cfg(not(feature = "native"))]
 mod client;
 mod connection;
+mod error;
+#[cfg(not(feature = "native"))]
+mod js;
 mod message;
+mod protocol;
+mod reconnect;
+mod runtime;
+mod state;
+mod trace;
+mod transport;
+mod url;
 mod utils;
 
+pub use builder::{
+    AccessTokenFactory, ConnectionOptions, HubConnectionBuilder, HubProtocol,
+    MessageHeadersFactory, OfflineQueue, OverflowPolicy, SubscriptionOptions, TransportType,
+};
+pub use connection::{
+    HandlerExecution, HandlerResult, Invocation, InvocationOptions, SignalRConnection,
+    IDEMPOTENCY_KEY_HEADER,
+};
+pub use error::{CloseInfo, SignalRError};
+pub use log::{DefaultLogger, LogLevel, Logger};
+pub use message::{CompletionMessage, InvocationMessage};
+pub use protocol::{HubProtocolState, ProtocolEvent};
+pub use reconnect::{DefaultReconnectPolicy, ReconnectPolicy, RetryContext};
+#[cfg(feature = "tokio-runtime")]
+pub use runtime::TokioRuntime;
+#[cfg(feature = "wasm-runtime")]
+pub use runtime::WasmRuntime;
+pub use runtime::{default_runtime, Runtime};
+pub use state::{ConnectionState, StateChange};
+pub use trace::{
+    InvocationSpan, TraceContext, TraceProvider, TRACEPARENT_HEADER, TRACESTATE_HEADER,
+};
+#[cfg(not(feature = "native"))]
+pub use transport::WebSocketFactory;
+pub use transport::{
+    MemoryListener, MemoryPeer, MemoryTransport, Transport, TransportEvent, TransportFactory,
+    WebSocketTransport, NORMAL_CLOSURE,
+};
+
 #[wasm_bindgen(start)]
 pub fn start() {
     utils::set_panic_hook();
-    console_log!("signalr-wasm: WASM loaded.");
 }
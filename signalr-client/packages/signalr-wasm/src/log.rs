@@ -1,18 +1,189 @@
+//! Leveled logging. Each connection filters messages by its
+//! [`log_level`](crate::ConnectionOptions::log_level) and hands the rest to its [`Logger`]:
+//! the `console.*` functions in wasm and the `log` crate natively by default.
+
+use std::fmt;
+use std::rc::Rc;
+
+#[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
+use crate::builder::ConnectionOptions;
+
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
-    pub fn log(s: &str);
+    fn debug(s: &str);
+
+    #[wasm_bindgen(js_namespace = console)]
+    fn info(s: &str);
+
+    #[wasm_bindgen(js_namespace = console)]
+    fn warn(s: &str);
 
     #[wasm_bindgen(js_namespace = console)]
     pub fn error(s: &str);
 }
 
-macro_rules! console_log {
-    ($($t:tt)*) => (crate::log::log(&format_args!($($t)*).to_string()))
+#[cfg(not(target_arch = "wasm32"))]
+pub fn error(s: &str) {
+    log::error!(target: "signalr_wasm", "{}", s);
 }
 
+/// For failures outside any connection, which have no logger to go to.
 macro_rules! console_error {
     ($($t:tt)*) => (crate::log::error(&format_args!($($t)*).to_string()))
 }
+
+/// Logs through a [`Log`](crate::log::Log) at `$level`, formatting the message only if that
+/// level is enabled.
+macro_rules! log_at {
+    ($log:expr, $level:expr, $($t:tt)*) => {{
+        let log: &crate::log::Log = &$log;
+        if log.enabled($level) {
+            log.write($level, format_args!($($t)*));
+        }
+    }};
+}
+
+macro_rules! log_trace {
+    ($log:expr, $($t:tt)*) => (log_at!($log, crate::log::LogLevel::Trace, $($t)*))
+}
+
+macro_rules! log_debug {
+    ($log:expr, $($t:tt)*) => (log_at!($log, crate::log::LogLevel::Debug, $($t)*))
+}
+
+macro_rules! log_info {
+    ($log:expr, $($t:tt)*) => (log_at!($log, crate::log::LogLevel::Info, $($t)*))
+}
+
+macro_rules! log_warn {
+    ($log:expr, $($t:tt)*) => (log_at!($log, crate::log::LogLevel::Warn, $($t)*))
+}
+
+macro_rules! log_error {
+    ($log:expr, $($t:tt)*) => (log_at!($log, crate::log::LogLevel::Error, $($t)*))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    None,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "trace",
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+            LogLevel::None => "none",
+        }
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "trace" => Ok(LogLevel::Trace),
+            "debug" => Ok(LogLevel::Debug),
+            "info" | "information" => Ok(LogLevel::Info),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            "none" => Ok(LogLevel::None),
+            other => Err(format!("Unknown log level `{}`", other)),
+        }
+    }
+}
+
+/// Where a connection's log messages go. Only called for levels the connection's
+/// [`log_level`](crate::ConnectionOptions::log_level) lets through, and never with
+/// [`LogLevel::None`].
+pub trait Logger {
+    fn log(&self, level: LogLevel, message: &str);
+}
+
+impl<F: Fn(LogLevel, &str)> Logger for F {
+    fn log(&self, level: LogLevel, message: &str) {
+        self(level, message)
+    }
+}
+
+/// Writes to `console.debug`, `console.info`, `console.warn` or `console.error` in wasm, and
+/// emits `log` records with the `signalr_wasm` target natively, which `tracing` subscribers
+/// receive through `tracing-log`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultLogger;
+
+impl Logger for DefaultLogger {
+    #[cfg(target_arch = "wasm32")]
+    fn log(&self, level: LogLevel, message: &str) {
+        match level {
+            LogLevel::Trace | LogLevel::Debug => debug(message),
+            LogLevel::Info => info(message),
+            LogLevel::Warn => warn(message),
+            LogLevel::Error => error(message),
+            LogLevel::None => {}
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn log(&self, level: LogLevel, message: &str) {
+        let level = match level {
+            LogLevel::Trace => log::Level::Trace,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Error => log::Level::Error,
+            LogLevel::None => return,
+        };
+
+        log::log!(target: "signalr_wasm", level, "{}", message);
+    }
+}
+
+/// A logger with the level it lets through.
+#[derive(Clone)]
+pub(crate) struct Log {
+    level: LogLevel,
+    logger: Rc<dyn Logger>,
+}
+
+impl Log {
+    pub(crate) fn new(level: LogLevel, logger: Rc<dyn Logger>) -> Self {
+        Self { level, logger }
+    }
+
+    pub(crate) fn from_options(options: &ConnectionOptions) -> Self {
+        let logger = options
+            .logger
+            .clone()
+            .unwrap_or_else(|| Rc::new(DefaultLogger));
+
+        Self::new(options.log_level, logger)
+    }
+
+    pub(crate) fn enabled(&self, level: LogLevel) -> bool {
+        level != LogLevel::None && level >= self.level
+    }
+
+    pub(crate) fn write(&self, level: LogLevel, message: fmt::Arguments<'_>) {
+        self.logger.log(level, &message.to_string());
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self::new(LogLevel::Info, Rc::new(DefaultLogger))
+    }
+}
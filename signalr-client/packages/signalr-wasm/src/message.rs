@@ -3,14 +3,22 @@ use serde::{
     Deserialize, Serialize,
 };
 use serde_json::Value;
+use std::collections::HashMap;
 
-#[derive(Deserialize, Debug)]
+use crate::trace::TraceContext;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct CompletionMessage {
     r#type: u64,
     pub invocation_id: String,
+    /// Left out when null, as servers reject completions with both a result and an error.
+    #[serde(default, skip_serializing_if = "Value::is_null")]
     pub result: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
 }
 
 impl CompletionMessage {
@@ -22,28 +30,76 @@ impl CompletionMessage {
             invocation_id,
             result,
             error,
+            headers: HashMap::new(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct InvocationMessage {
     r#type: u64,
-    #[serde(skip_deserializing)] // asp.net doesn't send them in invocations to us for some reason?
-    pub invocation_id: String,
+    /// Only set when the sender expects a [`CompletionMessage`] in return. Servers set it when
+    /// asking the client for a result.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invocation_id: Option<String>,
     pub target: String,
     pub arguments: Vec<Value>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
 }
 
 impl InvocationMessage {
     const TYPE: u64 = 1;
-    pub fn new(invocation_id: String, target: String, arguments: Vec<Value>) -> Self {
+    pub fn new(invocation_id: Option<String>, target: String, arguments: Vec<Value>) -> Self {
         Self {
             r#type: InvocationMessage::TYPE,
             invocation_id,
             target,
             arguments,
+            headers: HashMap::new(),
+        }
+    }
+
+    /// The trace context the sender propagated in the headers, if any.
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        TraceContext::from_headers(&self.headers)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CloseMessage {
+    r#type: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow_reconnect: Option<bool>,
+}
+
+impl CloseMessage {
+    const TYPE: u64 = 7;
+
+    pub fn new(error: Option<String>) -> Self {
+        Self {
+            r#type: CloseMessage::TYPE,
+            error,
+            allow_reconnect: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PingMessage {
+    r#type: u64,
+}
+
+impl PingMessage {
+    const TYPE: u64 = 6;
+
+    pub fn new() -> Self {
+        Self {
+            r#type: PingMessage::TYPE,
         }
     }
 }
@@ -58,11 +114,20 @@ impl InvocationMessage {
 //     Close = 7,
 // }
 
+/// The StreamItem, StreamInvocation and CancelInvocation message types, which belong to
+/// streaming invocations.
+const STREAM_ITEM_TYPE: u64 = 2;
+const STREAM_INVOCATION_TYPE: u64 = 4;
+const CANCEL_INVOCATION_TYPE: u64 = 5;
+
 #[derive(Debug)]
 pub enum SignalRMessage {
     Ping,
     Invocation(InvocationMessage),
     Completion(CompletionMessage),
+    Close(CloseMessage),
+    /// A valid message of a type this client doesn't support, with its type's name.
+    Unsupported(&'static str),
 }
 
 // Messages are  _almost_ an internally tagged enum, except Serde
@@ -82,7 +147,7 @@ impl<'de> Deserialize<'de> for SignalRMessage {
 
                 Ok(SignalRMessage::Invocation(inner_message))
             }
-            Some(2) => unimplemented!("can't deserialize StreamItem"),
+            Some(STREAM_ITEM_TYPE) => Ok(SignalRMessage::Unsupported("StreamItem")),
             Some(CompletionMessage::TYPE) => {
                 let inner_message = CompletionMessage::deserialize(value).map_err(|_| {
                     de::Error::invalid_type(Unexpected::StructVariant, &"a CompletionMessage")
@@ -90,10 +155,16 @@ impl<'de> Deserialize<'de> for SignalRMessage {
 
                 Ok(SignalRMessage::Completion(inner_message))
             }
-            Some(4) => unimplemented!("can't deserialize StreamInvocation"),
-            Some(5) => unimplemented!("can't deserialize CancelInvocation"),
-            Some(6) => Ok(SignalRMessage::Ping),
-            Some(7) => unimplemented!("can't deserialize Close"),
+            Some(STREAM_INVOCATION_TYPE) => Ok(SignalRMessage::Unsupported("StreamInvocation")),
+            Some(CANCEL_INVOCATION_TYPE) => Ok(SignalRMessage::Unsupported("CancelInvocation")),
+            Some(PingMessage::TYPE) => Ok(SignalRMessage::Ping),
+            Some(CloseMessage::TYPE) => {
+                let inner_message = CloseMessage::deserialize(value).map_err(|_| {
+                    de::Error::invalid_type(Unexpected::StructVariant, &"a CloseMessage")
+                })?;
+
+                Ok(SignalRMessage::Close(inner_message))
+            }
             Some(num) => Err(de::Error::invalid_value(
                 Unexpected::Unsigned(num),
                 &"type value between 1 and 7 inclusive",
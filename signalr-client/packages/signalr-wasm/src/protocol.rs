@@ -0,0 +1,673 @@
+//! The SignalR protocol without any I/O. [`HubProtocolState`] is fed received frames, commands
+//! and the current time, and in return produces frames to send, events and the next deadline
+//! it needs to be woken at. The connection drives it from its reader task, but nothing here
+//! depends on a particular executor or transport.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use crate::builder::{ConnectionOptions, HubProtocol};
+use crate::error::{CloseInfo, SignalRError};
+use crate::log::Log;
+use crate::message::{
+    CloseMessage, CompletionMessage, InvocationMessage, PingMessage, SignalRMessage,
+};
+
+const RECORD_SEPARATOR: char = '\x1E';
+
+#[derive(Serialize)]
+struct HandshakeRequest {
+    protocol: &'static str,
+    version: u8,
+}
+
+#[derive(Deserialize)]
+struct HandshakeResponse {
+    error: Option<String>,
+}
+
+/// Something the driver needs to act on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProtocolEvent {
+    HandshakeCompleted,
+    /// The handshake was rejected, timed out or interrupted. Nothing follows this.
+    HandshakeFailed(SignalRError),
+    Invocation(InvocationMessage),
+    /// The result of an invocation started with [`HubProtocolState::invoke`].
+    Completion(CompletionMessage),
+    /// The connection ended after the handshake. Nothing follows this.
+    Closed {
+        error: SignalRError,
+        allow_reconnect: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Handshaking { deadline: Duration },
+    Connected,
+    Closed,
+}
+
+/// The state of one connection attempt. Times are durations since an arbitrary, fixed
+/// origin chosen by the driver.
+pub struct HubProtocolState {
+    protocol: HubProtocol,
+    handshake_timeout: Duration,
+    server_timeout: Duration,
+    keep_alive_interval: Duration,
+    phase: Phase,
+    next_invocation_id: u64,
+    pending_invocations: HashSet<String>,
+    last_received: Duration,
+    last_sent: Duration,
+    transmit: VecDeque<String>,
+    events: VecDeque<ProtocolEvent>,
+    log: Log,
+}
+
+impl HubProtocolState {
+    pub fn new(options: &ConnectionOptions) -> Self {
+        Self {
+            protocol: options.protocol,
+            handshake_timeout: options.handshake_timeout,
+            server_timeout: options.server_timeout,
+            keep_alive_interval: options.keep_alive_interval,
+            phase: Phase::Idle,
+            next_invocation_id: 0,
+            pending_invocations: HashSet::new(),
+            last_received: Duration::ZERO,
+            last_sent: Duration::ZERO,
+            transmit: VecDeque::new(),
+            events: VecDeque::new(),
+            log: Log::from_options(options),
+        }
+    }
+
+    /// Queues the handshake request. The handshake times out at `now` plus the handshake
+    /// timeout, so calling this before opening the transport bounds both.
+    pub fn start(&mut self, now: Duration) -> Result<(), String> {
+        if self.phase != Phase::Idle {
+            return Err("The handshake has already been started".to_owned());
+        }
+
+        let request = HandshakeRequest {
+            protocol: self.protocol.name(),
+            version: self.protocol.version(),
+        };
+
+        self.send(&request, now)?;
+        self.phase = Phase::Handshaking {
+            deadline: now + self.handshake_timeout,
+        };
+
+        Ok(())
+    }
+
+    /// Handles a text frame from the transport, which may hold several messages.
+    pub fn handle_text(&mut self, text: &str, now: Duration) {
+        self.last_received = now;
+
+        for message in text.split_terminator(RECORD_SEPARATOR) {
+            match self.phase {
+                Phase::Handshaking { .. } => self.handle_handshake(message),
+                Phase::Connected => self.handle_message(message),
+                Phase::Idle | Phase::Closed => return,
+            }
+        }
+    }
+
+    /// Handles the transport closing.
+    pub fn handle_close(&mut self, info: CloseInfo) {
+        self.fail(SignalRError::SocketClosed(info), true);
+    }
+
+    /// Handles any deadline from [`HubProtocolState::poll_timeout`] that has passed: failing
+    /// the handshake, giving up on a silent server or queueing a keep-alive ping.
+    pub fn handle_timeout(&mut self, now: Duration) {
+        match self.phase {
+            Phase::Handshaking { deadline } if now >= deadline => {
+                self.fail(
+                    SignalRError::HandshakeTimeout(self.handshake_timeout),
+                    false,
+                );
+            }
+            Phase::Connected => {
+                if now >= self.last_received + self.server_timeout {
+                    self.fail(SignalRError::ServerTimeout(self.server_timeout), true);
+                } else if now >= self.last_sent + self.keep_alive_interval {
+                    if let Err(e) = self.send(&PingMessage::new(), now) {
+                        log_error!(self.log, "Failed to send ping: {}", e);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Queues an invocation, returning the id its [`ProtocolEvent::Completion`] will carry.
+    pub fn invoke(
+        &mut self,
+        target: String,
+        arguments: Vec<Value>,
+        now: Duration,
+    ) -> Result<String, String> {
+        self.invoke_with_headers(target, arguments, HashMap::new(), now)
+    }
+
+    /// Like [`HubProtocolState::invoke`], sending `headers` along with the invocation.
+    pub fn invoke_with_headers(
+        &mut self,
+        target: String,
+        arguments: Vec<Value>,
+        headers: HashMap<String, String>,
+        now: Duration,
+    ) -> Result<String, String> {
+        if self.phase != Phase::Connected {
+            return Err("The handshake has not completed".to_owned());
+        }
+
+        self.next_invocation_id += 1;
+        let invocation_id = self.next_invocation_id.to_string();
+        let mut invocation = InvocationMessage::new(Some(invocation_id.clone()), target, arguments);
+        invocation.headers = headers;
+
+        self.send(&invocation, now)?;
+        self.pending_invocations.insert(invocation_id.clone());
+
+        Ok(invocation_id)
+    }
+
+    /// Queues the client's result for an invocation the server sent with an id.
+    pub fn complete(
+        &mut self,
+        invocation_id: String,
+        result: Result<Value, String>,
+        now: Duration,
+    ) -> Result<(), String> {
+        self.complete_with_headers(invocation_id, result, HashMap::new(), now)
+    }
+
+    /// Like [`HubProtocolState::complete`], sending `headers` along with the completion.
+    pub fn complete_with_headers(
+        &mut self,
+        invocation_id: String,
+        result: Result<Value, String>,
+        headers: HashMap<String, String>,
+        now: Duration,
+    ) -> Result<(), String> {
+        if self.phase != Phase::Connected {
+            return Err("The connection is not open".to_owned());
+        }
+
+        let mut completion = match result {
+            Ok(result) => CompletionMessage::new(invocation_id, result, None),
+            Err(error) => CompletionMessage::new(invocation_id, Value::Null, Some(error)),
+        };
+        completion.headers = headers;
+
+        self.send(&completion, now)
+    }
+
+    /// Queues a Close message. Nothing is received or sent after it.
+    pub fn close(&mut self, error: Option<String>, now: Duration) {
+        if self.phase == Phase::Connected {
+            if let Err(e) = self.send(&CloseMessage::new(error), now) {
+                log_error!(self.log, "Failed to send close message: {}", e);
+            }
+        }
+
+        self.phase = Phase::Closed;
+        self.pending_invocations.clear();
+    }
+
+    /// The next frame to send.
+    pub fn poll_transmit(&mut self) -> Option<String> {
+        self.transmit.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<ProtocolEvent> {
+        self.events.pop_front()
+    }
+
+    pub(crate) fn log(&self) -> &Log {
+        &self.log
+    }
+
+    /// When [`HubProtocolState::handle_timeout`] next needs to be called, if at all.
+    pub fn poll_timeout(&self) -> Option<Duration> {
+        match self.phase {
+            Phase::Handshaking { deadline } => Some(deadline),
+            Phase::Connected => Some(std::cmp::min(
+                self.last_received + self.server_timeout,
+                self.last_sent + self.keep_alive_interval,
+            )),
+            Phase::Idle | Phase::Closed => None,
+        }
+    }
+
+    fn handle_handshake(&mut self, message: &str) {
+        let error = match serde_json::from_str::<HandshakeResponse>(message) {
+            Ok(HandshakeResponse { error: None }) => {
+                log_debug!(self.log, "Handshake completed");
+                self.phase = Phase::Connected;
+                self.events.push_back(ProtocolEvent::HandshakeCompleted);
+                return;
+            }
+            Ok(HandshakeResponse { error: Some(error) }) => {
+                format!("Handshake failed: Received handshake error: {}", error)
+            }
+            Err(e) => format!("Handshake failed: Failed to parse JSON: {}", e),
+        };
+
+        self.fail(error.into(), false);
+    }
+
+    fn handle_message(&mut self, message: &str) {
+        // Message bodies are left out, as they may hold user data.
+        match serde_json::from_str(message) {
+            Ok(SignalRMessage::Completion(m)) => {
+                log_trace!(
+                    self.log,
+                    "Received completion of invocation {}",
+                    m.invocation_id
+                );
+
+                if self.pending_invocations.remove(&m.invocation_id) {
+                    self.events.push_back(ProtocolEvent::Completion(m));
+                } else {
+                    log_warn!(
+                        self.log,
+                        "Failed to find subscriber for invocation ID {}",
+                        m.invocation_id
+                    );
+                }
+            }
+            Ok(SignalRMessage::Invocation(m)) => {
+                log_trace!(self.log, "Received invocation of `{}`", m.target);
+                self.events.push_back(ProtocolEvent::Invocation(m));
+            }
+            Ok(SignalRMessage::Ping) => {
+                log_trace!(self.log, "Received ping");
+            }
+            Ok(SignalRMessage::Unsupported(name)) => {
+                log_warn!(
+                    self.log,
+                    "Ignoring {} message, as streaming isn't supported",
+                    name
+                );
+            }
+            Ok(SignalRMessage::Close(m)) => {
+                log_info!(self.log, "Server closed the connection: {:?}", m.error);
+
+                self.fail(
+                    SignalRError::ConnectionClosed { reason: m.error },
+                    m.allow_reconnect.unwrap_or(false),
+                );
+            }
+            Err(e) => {
+                log_error!(self.log, "Failed to deserialize message: {}", e);
+            }
+        }
+    }
+
+    /// Ends the attempt with `error`, reported according to how far it got.
+    fn fail(&mut self, error: SignalRError, allow_reconnect: bool) {
+        let event = match self.phase {
+            Phase::Idle | Phase::Handshaking { .. } => ProtocolEvent::HandshakeFailed(error),
+            Phase::Connected => ProtocolEvent::Closed {
+                error,
+                allow_reconnect,
+            },
+            Phase::Closed => return,
+        };
+
+        self.phase = Phase::Closed;
+        self.pending_invocations.clear();
+        self.events.push_back(event);
+    }
+
+    fn send(&mut self, message: &impl Serialize, now: Duration) -> Result<(), String> {
+        let mut serialized =
+            serde_json::to_string(message).map_err(|e| format!("Failed to serialize: {}", e))?;
+        serialized.push(RECORD_SEPARATOR);
+
+        self.transmit.push_back(serialized);
+        self.last_sent = now;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn options() -> ConnectionOptions {
+        let mut options = ConnectionOptions::new("https://example.com/hub");
+        options.handshake_timeout = secs(15);
+        options.server_timeout = secs(30);
+        options.keep_alive_interval = secs(15);
+        options
+    }
+
+    fn connected() -> HubProtocolState {
+        let mut state = HubProtocolState::new(&options());
+        state.start(secs(0)).unwrap();
+        state.poll_transmit();
+        state.handle_text("{}\x1E", secs(1));
+        assert_eq!(state.poll_event(), Some(ProtocolEvent::HandshakeCompleted));
+        state
+    }
+
+    fn transmitted(state: &mut HubProtocolState) -> Value {
+        let frame = state.poll_transmit().expect("a frame to send");
+        serde_json::from_str(frame.strip_suffix('\x1E').unwrap()).unwrap()
+    }
+
+    #[test]
+    fn start_sends_the_handshake_request() {
+        let mut state = HubProtocolState::new(&options());
+        state.start(secs(0)).unwrap();
+
+        assert_eq!(
+            transmitted(&mut state),
+            json!({ "protocol": "json", "version": 1 })
+        );
+        assert_eq!(state.poll_timeout(), Some(secs(15)));
+    }
+
+    #[test]
+    fn handshake_response_completes_the_handshake() {
+        let mut state = connected();
+
+        assert_eq!(state.poll_event(), None);
+        assert_eq!(state.poll_timeout(), Some(secs(15)));
+    }
+
+    #[test]
+    fn handshake_error_fails_the_handshake() {
+        let mut state = HubProtocolState::new(&options());
+        state.start(secs(0)).unwrap();
+        state.handle_text("{\"error\":\"Unsupported protocol\"}\x1E", secs(1));
+
+        assert_eq!(
+            state.poll_event(),
+            Some(ProtocolEvent::HandshakeFailed(SignalRError::Other(
+                "Handshake failed: Received handshake error: Unsupported protocol".to_owned()
+            )))
+        );
+        assert_eq!(state.poll_timeout(), None);
+    }
+
+    #[test]
+    fn handshake_times_out() {
+        let mut state = HubProtocolState::new(&options());
+        state.start(secs(0)).unwrap();
+
+        state.handle_timeout(secs(14));
+        assert_eq!(state.poll_event(), None);
+
+        state.handle_timeout(secs(15));
+        assert_eq!(
+            state.poll_event(),
+            Some(ProtocolEvent::HandshakeFailed(
+                SignalRError::HandshakeTimeout(secs(15))
+            ))
+        );
+    }
+
+    #[test]
+    fn messages_in_the_handshake_frame_are_handled() {
+        let mut state = HubProtocolState::new(&options());
+        state.start(secs(0)).unwrap();
+        state.handle_text(
+            "{}\x1E{\"type\":1,\"target\":\"Send\",\"arguments\":[1]}\x1E",
+            secs(1),
+        );
+
+        assert_eq!(state.poll_event(), Some(ProtocolEvent::HandshakeCompleted));
+        assert_eq!(
+            state.poll_event(),
+            Some(ProtocolEvent::Invocation(InvocationMessage::new(
+                None,
+                "Send".to_owned(),
+                vec![json!(1)]
+            )))
+        );
+    }
+
+    #[test]
+    fn invocations_get_increasing_ids() {
+        let mut state = connected();
+
+        assert_eq!(
+            state.invoke("A".to_owned(), vec![], secs(2)),
+            Ok("1".to_owned())
+        );
+        assert_eq!(
+            state.invoke("B".to_owned(), vec![], secs(2)),
+            Ok("2".to_owned())
+        );
+        assert_eq!(
+            transmitted(&mut state),
+            json!({ "type": 1, "invocationId": "1", "target": "A", "arguments": [] })
+        );
+    }
+
+    #[test]
+    fn invocation_headers_are_sent_when_present() {
+        let mut state = connected();
+        let headers = HashMap::from([("Idempotency-Key".to_owned(), "abc:1".to_owned())]);
+
+        state
+            .invoke_with_headers("A".to_owned(), vec![], headers, secs(2))
+            .unwrap();
+
+        assert_eq!(
+            transmitted(&mut state),
+            json!({
+                "type": 1,
+                "invocationId": "1",
+                "target": "A",
+                "arguments": [],
+                "headers": { "Idempotency-Key": "abc:1" },
+            })
+        );
+    }
+
+    #[test]
+    fn invoking_before_the_handshake_fails() {
+        let mut state = HubProtocolState::new(&options());
+
+        assert!(state.invoke("A".to_owned(), vec![], secs(0)).is_err());
+    }
+
+    #[test]
+    fn completions_are_routed_to_pending_invocations_only() {
+        let mut state = connected();
+        let id = state.invoke("A".to_owned(), vec![], secs(2)).unwrap();
+
+        state.handle_text(
+            "{\"type\":3,\"invocationId\":\"1\",\"result\":5}\x1E\
+             {\"type\":3,\"invocationId\":\"1\",\"result\":6}\x1E",
+            secs(3),
+        );
+
+        assert_eq!(
+            state.poll_event(),
+            Some(ProtocolEvent::Completion(CompletionMessage::new(
+                id,
+                json!(5),
+                None
+            )))
+        );
+        assert_eq!(state.poll_event(), None);
+    }
+
+    #[test]
+    fn server_invocations_are_answered_with_completions() {
+        let mut state = connected();
+        state.handle_text(
+            "{\"type\":1,\"invocationId\":\"7\",\"target\":\"Confirm\",\"arguments\":[]}\x1E",
+            secs(2),
+        );
+
+        assert_eq!(
+            state.poll_event(),
+            Some(ProtocolEvent::Invocation(InvocationMessage::new(
+                Some("7".to_owned()),
+                "Confirm".to_owned(),
+                vec![]
+            )))
+        );
+
+        state
+            .complete("7".to_owned(), Ok(json!(true)), secs(3))
+            .unwrap();
+        state
+            .complete("8".to_owned(), Err("Declined".to_owned()), secs(3))
+            .unwrap();
+
+        assert_eq!(
+            transmitted(&mut state),
+            json!({ "type": 3, "invocationId": "7", "result": true })
+        );
+        assert_eq!(
+            transmitted(&mut state),
+            json!({ "type": 3, "invocationId": "8", "error": "Declined" })
+        );
+    }
+
+    /// Streaming messages are ignored, leaving the connection usable.
+    fn ignores_streaming_message(message: Value) {
+        let mut state = connected();
+        let id = state.invoke("A".to_owned(), vec![], secs(2)).unwrap();
+        transmitted(&mut state);
+
+        state.handle_text(&format!("{}\x1E", message), secs(3));
+        assert_eq!(state.poll_event(), None);
+        assert_eq!(state.poll_transmit(), None);
+
+        let completion = json!({ "type": 3, "invocationId": id, "result": 5 });
+        state.handle_text(&format!("{}\x1E", completion), secs(4));
+        assert!(matches!(
+            state.poll_event(),
+            Some(ProtocolEvent::Completion(_))
+        ));
+    }
+
+    #[test]
+    fn stream_items_are_ignored() {
+        ignores_streaming_message(json!({ "type": 2, "invocationId": "1", "item": 5 }));
+    }
+
+    #[test]
+    fn stream_invocations_are_ignored() {
+        ignores_streaming_message(json!({
+            "type": 4,
+            "invocationId": "9",
+            "target": "Counter",
+            "arguments": [],
+        }));
+    }
+
+    #[test]
+    fn cancel_invocations_are_ignored() {
+        ignores_streaming_message(json!({ "type": 5, "invocationId": "9" }));
+    }
+
+    #[test]
+    fn keep_alive_pings_when_nothing_was_sent() {
+        let mut state = connected();
+        state.handle_text("{\"type\":6}\x1E", secs(10));
+
+        state.handle_timeout(secs(14));
+        assert_eq!(state.poll_transmit(), None);
+
+        state.handle_timeout(secs(15));
+        assert_eq!(transmitted(&mut state), json!({ "type": 6 }));
+        assert_eq!(state.poll_timeout(), Some(secs(30)));
+    }
+
+    #[test]
+    fn sending_delays_the_keep_alive() {
+        let mut state = connected();
+        state.invoke("A".to_owned(), vec![], secs(10)).unwrap();
+        state.handle_text("{\"type\":6}\x1E", secs(10));
+
+        assert_eq!(state.poll_timeout(), Some(secs(25)));
+    }
+
+    #[test]
+    fn server_timeout_closes_the_connection() {
+        let mut state = connected();
+
+        state.handle_timeout(secs(31));
+
+        assert_eq!(
+            state.poll_event(),
+            Some(ProtocolEvent::Closed {
+                error: SignalRError::ServerTimeout(secs(30)),
+                allow_reconnect: true,
+            })
+        );
+        assert_eq!(state.poll_timeout(), None);
+    }
+
+    #[test]
+    fn close_message_from_the_server_closes_the_connection() {
+        let mut state = connected();
+        state.handle_text(
+            "{\"type\":7,\"error\":\"Shutting down\",\"allowReconnect\":true}\x1E",
+            secs(2),
+        );
+
+        assert_eq!(
+            state.poll_event(),
+            Some(ProtocolEvent::Closed {
+                error: SignalRError::ConnectionClosed {
+                    reason: Some("Shutting down".to_owned())
+                },
+                allow_reconnect: true,
+            })
+        );
+    }
+
+    #[test]
+    fn transport_closing_during_the_handshake_fails_it() {
+        let mut state = HubProtocolState::new(&options());
+        state.start(secs(0)).unwrap();
+
+        let info = CloseInfo {
+            code: 1006,
+            reason: String::new(),
+            was_clean: false,
+        };
+        state.handle_close(info.clone());
+
+        assert_eq!(
+            state.poll_event(),
+            Some(ProtocolEvent::HandshakeFailed(SignalRError::SocketClosed(
+                info
+            )))
+        );
+    }
+
+    #[test]
+    fn close_sends_a_close_message() {
+        let mut state = connected();
+        state.close(None, secs(2));
+
+        assert_eq!(transmitted(&mut state), json!({ "type": 7 }));
+        assert_eq!(state.poll_timeout(), None);
+    }
+}
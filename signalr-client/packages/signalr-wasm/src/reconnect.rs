@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+/// Information about the connection that was lost, passed to a [`ReconnectPolicy`].
+#[derive(Clone, Debug)]
+pub struct RetryContext {
+    /// How many reconnect attempts have already been made since the connection was lost.
+    pub previous_retry_count: u32,
+    /// Why the connection was lost, or why the previous attempt failed.
+    pub retry_reason: String,
+}
+
+pub trait ReconnectPolicy {
+    /// Returns how long to wait before the next reconnect attempt, or `None` to give up.
+    fn next_retry_delay(&self, context: &RetryContext) -> Option<Duration>;
+}
+
+/// Retries after a fixed list of delays, then gives up. Defaults to the same
+/// 0, 2, 10 and 30 second delays as the official clients.
+#[derive(Clone, Debug)]
+pub struct DefaultReconnectPolicy {
+    delays: Vec<Duration>,
+}
+
+impl DefaultReconnectPolicy {
+    pub fn new(delays: Vec<Duration>) -> Self {
+        Self { delays }
+    }
+}
+
+impl Default for DefaultReconnectPolicy {
+    fn default() -> Self {
+        Self::new(vec![
+            Duration::from_secs(0),
+            Duration::from_secs(2),
+            Duration::from_secs(10),
+            Duration::from_secs(30),
+        ])
+    }
+}
+
+impl ReconnectPolicy for DefaultReconnectPolicy {
+    fn next_retry_delay(&self, context: &RetryContext) -> Option<Duration> {
+        self.delays
+            .get(context.previous_retry_count as usize)
+            .copied()
+    }
+}
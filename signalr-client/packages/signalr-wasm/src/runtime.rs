@@ -0,0 +1,159 @@
+//! Where a connection spawns its tasks and how it waits. [`WasmRuntime`] and [`TokioRuntime`]
+//! are available behind the `wasm-runtime` and `tokio-runtime` features, and anything else can
+//! be plugged in with [`HubConnectionBuilder::with_runtime`](crate::HubConnectionBuilder::with_runtime).
+
+use futures::future::{self, LocalBoxFuture};
+use futures::FutureExt;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Spawns the connection's tasks and provides its timers. Connection tasks aren't `Send`, so
+/// they must run on the thread that spawned them.
+pub trait Runtime {
+    fn spawn_local(&self, future: LocalBoxFuture<'static, ()>);
+
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()>;
+
+    /// The current time, measured from any fixed origin.
+    fn now(&self) -> Duration;
+
+    /// Resolves once [`Runtime::now`] reaches `deadline`, or never if there isn't one.
+    fn sleep_until(&self, deadline: Option<Duration>) -> LocalBoxFuture<'static, ()> {
+        match deadline {
+            Some(deadline) => self.sleep(deadline.saturating_sub(self.now())),
+            None => future::pending().boxed_local(),
+        }
+    }
+}
+
+/// The runtime used when none is configured: [`TokioRuntime`] if the `tokio-runtime` feature
+/// is enabled, otherwise [`WasmRuntime`] if `wasm-runtime` is.
+#[cfg(feature = "tokio-runtime")]
+pub fn default_runtime() -> Option<Rc<dyn Runtime>> {
+    Some(Rc::new(TokioRuntime::new()))
+}
+
+#[cfg(all(feature = "wasm-runtime", not(feature = "tokio-runtime")))]
+pub fn default_runtime() -> Option<Rc<dyn Runtime>> {
+    Some(Rc::new(WasmRuntime))
+}
+
+#[cfg(not(any(feature = "wasm-runtime", feature = "tokio-runtime")))]
+pub fn default_runtime() -> Option<Rc<dyn Runtime>> {
+    None
+}
+
+/// Spawns with `wasm_bindgen_futures` and sleeps with the global `setTimeout`.
+#[cfg(feature = "wasm-runtime")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WasmRuntime;
+
+#[cfg(feature = "wasm-runtime")]
+mod wasm {
+    use js_sys::{Function, Promise};
+    use wasm_bindgen::prelude::*;
+    use wasm_bindgen_futures::JsFuture;
+
+    #[wasm_bindgen]
+    extern "C" {
+        #[wasm_bindgen(js_name = setTimeout)]
+        fn set_timeout(handler: &Function, timeout: i32) -> JsValue;
+
+        #[wasm_bindgen(js_name = clearTimeout)]
+        fn clear_timeout(handle: &JsValue);
+
+        /// Global in browsers, workers and Node.
+        #[wasm_bindgen(js_namespace = performance, js_name = now)]
+        pub fn performance_now() -> f64;
+    }
+
+    /// A `setTimeout` timer, cleared if dropped before it fires so idle timers don't pile up.
+    pub struct Timeout {
+        handle: JsValue,
+        pub fired: JsFuture,
+    }
+
+    impl Timeout {
+        pub fn new(milliseconds: i32) -> Self {
+            let mut handle = JsValue::UNDEFINED;
+            let promise = Promise::new(&mut |resolve, _| {
+                handle = set_timeout(&resolve, milliseconds);
+            });
+
+            Self {
+                handle,
+                fired: JsFuture::from(promise),
+            }
+        }
+    }
+
+    impl Drop for Timeout {
+        fn drop(&mut self) {
+            clear_timeout(&self.handle);
+        }
+    }
+}
+
+#[cfg(feature = "wasm-runtime")]
+impl Runtime for WasmRuntime {
+    fn spawn_local(&self, future: LocalBoxFuture<'static, ()>) {
+        wasm_bindgen_futures::spawn_local(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        let milliseconds = duration.as_millis().min(i32::MAX as u128) as i32;
+        let mut timeout = wasm::Timeout::new(milliseconds);
+
+        async move {
+            if let Err(e) = (&mut timeout.fired).await {
+                console_error!("Timer failed: {:?}", e);
+            }
+        }
+        .boxed_local()
+    }
+
+    /// The time since the page or process started, according to `performance.now()`, which
+    /// unlike `Date.now()` never jumps when the system clock is adjusted.
+    fn now(&self) -> Duration {
+        Duration::from_secs_f64(wasm::performance_now() / 1000.0)
+    }
+}
+
+/// Spawns onto the current `tokio::task::LocalSet` and sleeps with tokio's timer.
+#[cfg(feature = "tokio-runtime")]
+#[derive(Clone, Copy, Debug)]
+pub struct TokioRuntime {
+    start: tokio::time::Instant,
+}
+
+#[cfg(feature = "tokio-runtime")]
+impl Default for TokioRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "tokio-runtime")]
+impl TokioRuntime {
+    pub fn new() -> Self {
+        Self {
+            start: tokio::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "tokio-runtime")]
+impl Runtime for TokioRuntime {
+    fn spawn_local(&self, future: LocalBoxFuture<'static, ()>) {
+        tokio::task::spawn_local(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed_local()
+    }
+
+    /// The time since this runtime was created, following tokio's clock.
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
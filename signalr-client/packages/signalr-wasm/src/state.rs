@@ -0,0 +1,127 @@
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+
+use crate::error::SignalRError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+    Reconnecting,
+    Disconnecting,
+}
+
+impl ConnectionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Disconnected => "Disconnected",
+            ConnectionState::Connecting => "Connecting",
+            ConnectionState::Connected => "Connected",
+            ConnectionState::Reconnecting => "Reconnecting",
+            ConnectionState::Disconnecting => "Disconnecting",
+        }
+    }
+
+    fn can_transition_to(self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+
+        matches!(
+            (self, next),
+            (Disconnected, Connecting)
+                | (Connecting, Connected)
+                | (Connecting, Disconnecting)
+                | (Connecting, Disconnected)
+                | (Connected, Reconnecting)
+                | (Connected, Disconnecting)
+                | (Connected, Disconnected)
+                | (Reconnecting, Connected)
+                | (Reconnecting, Disconnecting)
+                | (Reconnecting, Disconnected)
+                | (Disconnecting, Disconnected)
+        )
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateChange {
+    pub previous: ConnectionState,
+    pub current: ConnectionState,
+    /// Set when the change was caused by a failure, such as the socket closing.
+    pub error: Option<SignalRError>,
+}
+
+/// Holds the current [`ConnectionState`] and notifies watchers whenever it changes.
+pub(crate) struct StateMachine {
+    state: Cell<ConnectionState>,
+    watchers: RefCell<Vec<UnboundedSender<StateChange>>>,
+}
+
+impl StateMachine {
+    pub fn new() -> Self {
+        Self {
+            state: Cell::new(ConnectionState::Disconnected),
+            watchers: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn get(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    /// Moves to `next` if the current state is one of `from` and the transition is legal,
+    /// otherwise fails with [`SignalRError::InvalidState`] naming `operation`.
+    pub fn transition(
+        &self,
+        operation: &'static str,
+        from: &[ConnectionState],
+        next: ConnectionState,
+    ) -> Result<ConnectionState, SignalRError> {
+        self.transition_with_error(operation, from, next, None)
+    }
+
+    /// Like [`StateMachine::transition`], but tells watchers which failure caused the change.
+    pub fn transition_with_error(
+        &self,
+        operation: &'static str,
+        from: &[ConnectionState],
+        next: ConnectionState,
+        error: Option<SignalRError>,
+    ) -> Result<ConnectionState, SignalRError> {
+        let previous = self.state.get();
+
+        if !from.contains(&previous) || !previous.can_transition_to(next) {
+            return Err(SignalRError::InvalidState {
+                operation,
+                state: previous,
+            });
+        }
+
+        self.state.set(next);
+
+        let change = StateChange {
+            previous,
+            current: next,
+            error,
+        };
+        self.watchers
+            .borrow_mut()
+            .retain(|watcher| watcher.unbounded_send(change.clone()).is_ok());
+
+        Ok(previous)
+    }
+
+    pub fn watch(&self) -> UnboundedReceiver<StateChange> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.watchers.borrow_mut().push(sender);
+
+        receiver
+    }
+}
@@ -0,0 +1,227 @@
+//! W3C Trace Context propagation. With a [`TraceProvider`] configured, each invocation gets a
+//! client-side span, a child of the provider's current context, which is sent to the server
+//! in the `traceparent` and `tracestate` headers and reported back when the invocation ends.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::runtime::Runtime;
+use crate::utils;
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+/// A position in a distributed trace, as carried by the `traceparent` and `tracestate`
+/// headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits.
+    pub trace_id: String,
+    /// The id of the span this context belongs to, 16 lowercase hex digits.
+    pub span_id: String,
+    pub flags: u8,
+    /// Vendor-specific data, passed along unchanged.
+    pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+    /// Reads a `traceparent` header, returning `None` if it's malformed.
+    pub fn parse(traceparent: &str, trace_state: Option<&str>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+
+        let is_hex = |s: &str, len: usize| {
+            s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        // Later versions may append fields, which version 00 parsers ignore.
+        let valid = is_hex(version, 2)
+            && version != "ff"
+            && (version != "00" || parts.next().is_none())
+            && is_hex(trace_id, 32)
+            && trace_id.bytes().any(|b| b != b'0')
+            && is_hex(span_id, 16)
+            && span_id.bytes().any(|b| b != b'0')
+            && is_hex(flags, 2);
+
+        if !valid {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: trace_id.to_owned(),
+            span_id: span_id.to_owned(),
+            flags: u8::from_str_radix(flags, 16).ok()?,
+            trace_state: trace_state.filter(|s| !s.is_empty()).map(str::to_owned),
+        })
+    }
+
+    /// Reads the context from a message's headers.
+    pub fn from_headers(headers: &HashMap<String, String>) -> Option<Self> {
+        let trace_state = headers.get(TRACESTATE_HEADER).map(String::as_str);
+
+        Self::parse(headers.get(TRACEPARENT_HEADER)?, trace_state)
+    }
+
+    /// Starts a new, sampled trace.
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: format!("{}{}", utils::random_id(), utils::random_id()),
+            span_id: utils::random_id(),
+            flags: 1,
+            trace_state: None,
+        }
+    }
+
+    /// A new span in the same trace.
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: utils::random_id(),
+            ..self.clone()
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+
+    /// Adds the `traceparent` and `tracestate` headers, unless a `traceparent` is already set.
+    pub fn inject(&self, headers: &mut HashMap<String, String>) {
+        if headers.contains_key(TRACEPARENT_HEADER) {
+            return;
+        }
+
+        headers.insert(TRACEPARENT_HEADER.to_owned(), self.traceparent());
+        if let Some(trace_state) = &self.trace_state {
+            headers.insert(TRACESTATE_HEADER.to_owned(), trace_state.clone());
+        }
+    }
+}
+
+/// An invocation's client-side span, from the call to `invoke` until its result arrives or it
+/// fails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvocationSpan {
+    pub target: String,
+    /// The span's own context, which was sent to the server.
+    pub context: TraceContext,
+    /// The span id of the provider's context it was started in, if there was one.
+    pub parent_span_id: Option<String>,
+    /// When the invocation was made, on the connection's runtime clock.
+    pub start: Duration,
+    pub duration: Duration,
+    /// The hub method's error, or why the invocation failed on the client.
+    pub error: Option<String>,
+}
+
+/// Connects invocations to the application's tracing.
+pub trait TraceProvider {
+    /// The context invocations made right now belong to, usually the active span's. `None`
+    /// starts a new trace for each invocation.
+    fn current_context(&self) -> Option<TraceContext>;
+
+    /// Called with each invocation's span once it ends, including when the invocation is
+    /// rejected right away or fails because the connection was stopped or dropped.
+    fn on_span_end(&self, span: InvocationSpan) {
+        let _ = span;
+    }
+}
+
+/// A span that's been started but not reported yet.
+pub(crate) struct ActiveSpan {
+    provider: Rc<dyn TraceProvider>,
+    runtime: Rc<dyn Runtime>,
+    target: String,
+    context: TraceContext,
+    parent_span_id: Option<String>,
+    start: Duration,
+}
+
+impl ActiveSpan {
+    pub(crate) fn start(
+        provider: Rc<dyn TraceProvider>,
+        runtime: Rc<dyn Runtime>,
+        target: &str,
+    ) -> Self {
+        let parent = provider.current_context();
+        let context = parent
+            .as_ref()
+            .map_or_else(TraceContext::new_root, TraceContext::child);
+
+        Self {
+            start: runtime.now(),
+            provider,
+            runtime,
+            target: target.to_owned(),
+            context,
+            parent_span_id: parent.map(|p| p.span_id),
+        }
+    }
+
+    pub(crate) fn context(&self) -> &TraceContext {
+        &self.context
+    }
+
+    pub(crate) fn end(self, error: Option<String>) {
+        let span = InvocationSpan {
+            duration: self.runtime.now().saturating_sub(self.start),
+            target: self.target,
+            context: self.context,
+            parent_span_id: self.parent_span_id,
+            start: self.start,
+            error,
+        };
+
+        self.provider.on_span_end(span);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn traceparents_round_trip() {
+        let context = TraceContext::parse(TRACEPARENT, Some("congo=t61rcWkgMzE")).unwrap();
+
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.span_id, "00f067aa0ba902b7");
+        assert_eq!(context.flags, 1);
+        assert_eq!(context.trace_state.as_deref(), Some("congo=t61rcWkgMzE"));
+        assert_eq!(context.traceparent(), TRACEPARENT);
+    }
+
+    #[test]
+    fn malformed_traceparents_are_rejected() {
+        for traceparent in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(
+                TraceContext::parse(traceparent, None),
+                None,
+                "{}",
+                traceparent
+            );
+        }
+    }
+
+    #[test]
+    fn children_keep_the_trace_and_get_a_new_span() {
+        let parent = TraceContext::parse(TRACEPARENT, Some("congo=t61rcWkgMzE")).unwrap();
+        let child = parent.child();
+
+        assert_eq!(child.trace_id, parent.trace_id);
+        assert_ne!(child.span_id, parent.span_id);
+        assert_eq!(child.trace_state, parent.trace_state);
+        assert!(TraceContext::parse(&child.traceparent(), None).is_some());
+    }
+}
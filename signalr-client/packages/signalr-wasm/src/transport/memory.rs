@@ -0,0 +1,196 @@
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::future::LocalBoxFuture;
+use futures::stream::{AbortHandle, Abortable, LocalBoxStream};
+use futures::{FutureExt, StreamExt};
+use std::collections::HashMap;
+
+use crate::error::{CloseInfo, SignalRError};
+use crate::transport::{Transport, TransportEvent, ABNORMAL_CLOSURE};
+
+/// Stands in for a server in tests, accepting connections made by the
+/// [`MemoryTransport`]s its connector creates.
+pub struct MemoryListener {
+    sender: UnboundedSender<MemoryPeer>,
+    receiver: UnboundedReceiver<MemoryPeer>,
+}
+
+impl Default for MemoryListener {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryListener {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+
+        Self { sender, receiver }
+    }
+
+    /// Creates transports connecting to this listener, for
+    /// [`HubConnectionBuilder::with_transport`](crate::HubConnectionBuilder::with_transport).
+    pub fn connector(&self) -> impl Fn() -> MemoryTransport + 'static {
+        let sender = self.sender.clone();
+
+        move || MemoryTransport {
+            listener: sender.clone(),
+            peer: None,
+            abort: None,
+        }
+    }
+
+    /// Waits for a transport to connect.
+    pub async fn accept(&mut self) -> Option<MemoryPeer> {
+        self.receiver.next().await
+    }
+}
+
+/// A transport whose other end is a [`MemoryPeer`], accepted from the [`MemoryListener`]
+/// whose connector created it.
+pub struct MemoryTransport {
+    listener: UnboundedSender<MemoryPeer>,
+    peer: Option<UnboundedSender<TransportEvent>>,
+    abort: Option<AbortHandle>,
+}
+
+impl MemoryTransport {
+    fn send(&self, event: TransportEvent) -> Result<(), String> {
+        match &self.peer {
+            Some(peer) => peer
+                .unbounded_send(event)
+                .map_err(|_| "Peer has disconnected".to_owned()),
+            None => Err("Not connected".to_owned()),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn connect<'a>(
+        &'a mut self,
+        url: &'a str,
+        headers: &'a HashMap<String, String>,
+    ) -> LocalBoxFuture<'a, Result<LocalBoxStream<'static, TransportEvent>, SignalRError>> {
+        async move {
+            if self.peer.is_some() {
+                return Err("Already connected".to_owned().into());
+            }
+
+            let (to_peer, from_client) = mpsc::unbounded();
+            let (to_client, from_peer) = mpsc::unbounded();
+            let peer = MemoryPeer {
+                url: url.to_owned(),
+                headers: headers.clone(),
+                to_client: Some(to_client),
+                from_client,
+            };
+
+            self.listener
+                .unbounded_send(peer)
+                .map_err(|_| "No listener is accepting connections".to_owned())?;
+
+            let (abort, registration) = AbortHandle::new_pair();
+            self.peer = Some(to_peer);
+            self.abort = Some(abort);
+
+            Ok(Abortable::new(from_peer, registration).boxed_local())
+        }
+        .boxed_local()
+    }
+
+    fn send_text(&self, text: &str) -> Result<(), String> {
+        self.send(TransportEvent::Text(text.to_owned()))
+    }
+
+    fn send_binary(&self, data: &[u8]) -> Result<(), String> {
+        self.send(TransportEvent::Binary(data.to_vec()))
+    }
+
+    fn close(&self, code: u16) {
+        if let Some(abort) = &self.abort {
+            abort.abort();
+        }
+
+        if let Some(peer) = &self.peer {
+            let _ = peer.unbounded_send(TransportEvent::Closed(CloseInfo {
+                code,
+                reason: String::new(),
+                was_clean: true,
+            }));
+            peer.close_channel();
+        }
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        if let Some(abort) = &self.abort {
+            abort.abort();
+        }
+    }
+}
+
+/// The server's end of a [`MemoryTransport`]. Dropping it without calling
+/// [`MemoryPeer::close`] closes the transport uncleanly.
+pub struct MemoryPeer {
+    url: String,
+    headers: HashMap<String, String>,
+    to_client: Option<UnboundedSender<TransportEvent>>,
+    from_client: UnboundedReceiver<TransportEvent>,
+}
+
+impl MemoryPeer {
+    /// The URL the transport connected to.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    pub fn send_text(&self, text: &str) -> Result<(), String> {
+        self.send(TransportEvent::Text(text.to_owned()))
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> Result<(), String> {
+        self.send(TransportEvent::Binary(data.to_vec()))
+    }
+
+    fn send(&self, event: TransportEvent) -> Result<(), String> {
+        self.to_client
+            .as_ref()
+            .ok_or_else(|| "Already closed".to_owned())?
+            .unbounded_send(event)
+            .map_err(|_| "Transport has disconnected".to_owned())
+    }
+
+    /// Waits for the next frame from the transport. Yields `Closed` when the transport is
+    /// closed, and `None` once it's gone.
+    pub async fn receive(&mut self) -> Option<TransportEvent> {
+        self.from_client.next().await
+    }
+
+    pub fn close(mut self, code: u16, reason: &str) {
+        self.close_with(CloseInfo {
+            code,
+            reason: reason.to_owned(),
+            was_clean: true,
+        });
+    }
+
+    fn close_with(&mut self, info: CloseInfo) {
+        if let Some(to_client) = self.to_client.take() {
+            let _ = to_client.unbounded_send(TransportEvent::Closed(info));
+        }
+    }
+}
+
+impl Drop for MemoryPeer {
+    fn drop(&mut self) {
+        self.close_with(CloseInfo {
+            code: ABNORMAL_CLOSURE,
+            reason: String::new(),
+            was_clean: false,
+        });
+    }
+}
@@ -0,0 +1,54 @@
+//! How a connection exchanges frames with the server. [`WebSocketTransport`] is used unless
+//! [`ConnectionOptions::transport_factory`](crate::ConnectionOptions::transport_factory) says
+//! otherwise; [`MemoryTransport`] connects to an in-process peer instead, for tests.
+
+use futures::future::LocalBoxFuture;
+use futures::stream::LocalBoxStream;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::error::{CloseInfo, SignalRError};
+
+mod memory;
+mod websocket;
+
+pub use memory::{MemoryListener, MemoryPeer, MemoryTransport};
+#[cfg(not(feature = "native"))]
+pub(crate) use websocket::constructor_factory;
+#[cfg(not(feature = "native"))]
+pub use websocket::WebSocketFactory;
+pub use websocket::WebSocketTransport;
+
+/// The WebSocket close code for a normal closure.
+pub const NORMAL_CLOSURE: u16 = 1000;
+
+/// The close code browsers report when a connection drops without a close frame.
+pub(crate) const ABNORMAL_CLOSURE: u16 = 1006;
+
+/// Creates a transport for each connection attempt.
+pub type TransportFactory = Rc<dyn Fn() -> Box<dyn Transport>>;
+
+/// Something that happened on an open transport. Nothing follows `Closed`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransportEvent {
+    Text(String),
+    Binary(Vec<u8>),
+    Closed(CloseInfo),
+}
+
+/// A duplex, frame-based connection to the server. A transport is connected at most once.
+pub trait Transport {
+    /// Opens the transport, resolving with the stream of incoming events once it's open.
+    fn connect<'a>(
+        &'a mut self,
+        url: &'a str,
+        headers: &'a HashMap<String, String>,
+    ) -> LocalBoxFuture<'a, Result<LocalBoxStream<'static, TransportEvent>, SignalRError>>;
+
+    fn send_text(&self, text: &str) -> Result<(), String>;
+
+    fn send_binary(&self, data: &[u8]) -> Result<(), String>;
+
+    /// Closes the transport. Its event stream ends without a `Closed` event.
+    fn close(&self, code: u16);
+}
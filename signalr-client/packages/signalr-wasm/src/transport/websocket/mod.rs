@@ -0,0 +1,146 @@
+use futures::future::LocalBoxFuture;
+use futures::stream::LocalBoxStream;
+use futures::{FutureExt, StreamExt};
+use std::collections::HashMap;
+
+#[cfg(not(feature = "native"))]
+use js_sys::Function;
+#[cfg(not(feature = "native"))]
+pub(crate) use web::constructor_factory;
+#[cfg(not(feature = "native"))]
+pub use web::WebSocketFactory;
+
+use crate::builder::ConnectionOptions;
+use crate::error::SignalRError;
+use crate::log::Log;
+use crate::transport::{Transport, TransportEvent};
+
+#[cfg(feature = "native")]
+mod native;
+#[cfg(not(feature = "native"))]
+mod web;
+
+#[cfg(feature = "native")]
+use native::Socket;
+#[cfg(not(feature = "native"))]
+use web::Socket;
+
+/// A WebSocket: the JavaScript `WebSocket` by default, or tokio-tungstenite with the `native`
+/// feature. Dropping it closes the socket.
+pub struct WebSocketTransport {
+    #[cfg(feature = "native")]
+    capacity: usize,
+    #[cfg(not(feature = "native"))]
+    factory: Option<WebSocketFactory>,
+    log: Log,
+    socket: Option<Socket>,
+}
+
+#[cfg(not(feature = "native"))]
+impl Default for WebSocketTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebSocketTransport {
+    /// `capacity` bounds the number of received frames waiting to be read. Only the native
+    /// backend has this limit.
+    #[cfg(feature = "native")]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            log: Log::default(),
+            socket: None,
+        }
+    }
+
+    /// Every received frame is queued until it's read, as a JavaScript `WebSocket` can't be
+    /// paused.
+    #[cfg(not(feature = "native"))]
+    pub fn new() -> Self {
+        Self {
+            factory: None,
+            log: Log::default(),
+            socket: None,
+        }
+    }
+
+    /// Creates the socket with `constructor` instead of the global `WebSocket`, such as the
+    /// `ws` package's in Node. Configured headers are passed to it as `ws` expects.
+    #[cfg(not(feature = "native"))]
+    pub fn with_constructor(self, constructor: Function) -> Self {
+        self.with_factory(constructor_factory(constructor))
+    }
+
+    /// Creates the socket with `factory`, which may return a fake or wrapped socket.
+    #[cfg(not(feature = "native"))]
+    pub fn with_factory(mut self, factory: WebSocketFactory) -> Self {
+        self.factory = Some(factory);
+        self
+    }
+
+    /// The transport used when no transport factory is configured.
+    pub(crate) fn from_options(options: &ConnectionOptions) -> Self {
+        #[cfg(feature = "native")]
+        let transport = Self::new(options.channel_capacity);
+        #[cfg(not(feature = "native"))]
+        let transport = Self::new();
+
+        #[cfg(not(feature = "native"))]
+        let transport = match &options.websocket_factory {
+            Some(factory) => transport.with_factory(factory.clone()),
+            None => transport,
+        };
+
+        Self {
+            log: Log::from_options(options),
+            ..transport
+        }
+    }
+
+    fn socket(&self) -> Result<&Socket, String> {
+        self.socket
+            .as_ref()
+            .ok_or_else(|| "No open socket".to_owned())
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn connect<'a>(
+        &'a mut self,
+        url: &'a str,
+        headers: &'a HashMap<String, String>,
+    ) -> LocalBoxFuture<'a, Result<LocalBoxStream<'static, TransportEvent>, SignalRError>> {
+        async move {
+            if self.socket.is_some() {
+                return Err("Already connected".to_owned().into());
+            }
+
+            #[cfg(not(feature = "native"))]
+            let connecting = Socket::connect(url, headers, self.factory.as_ref(), &self.log);
+            #[cfg(feature = "native")]
+            let connecting = Socket::connect(url, headers, self.capacity, &self.log);
+
+            let (socket, events) = connecting.await?;
+            self.socket = Some(socket);
+
+            Ok(events.boxed_local())
+        }
+        .boxed_local()
+    }
+
+    fn send_text(&self, text: &str) -> Result<(), String> {
+        self.socket()?.send_text(text)
+    }
+
+    fn send_binary(&self, data: &[u8]) -> Result<(), String> {
+        self.socket()?.send_binary(data)
+    }
+
+    fn close(&self, code: u16) {
+        if let Some(socket) = &self.socket {
+            socket.close(code);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use futures::channel::mpsc::{self, Receiver, UnboundedSender};
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error, Message};
+
+use crate::error::{CloseInfo, SignalRError};
+use crate::log::Log;
+use crate::transport::{TransportEvent, ABNORMAL_CLOSURE, NORMAL_CLOSURE};
+
+/// A tokio-tungstenite WebSocket, split into a task writing queued messages and a task
+/// reading incoming ones. The writer runs on the current `LocalSet`, like the connection's own
+/// tasks, so it can log through the connection. Dropping it closes the socket.
+pub struct Socket {
+    outgoing: UnboundedSender<Message>,
+    reader: JoinHandle<()>,
+}
+
+impl Socket {
+    /// Opens a socket, sending `headers` with the upgrade request.
+    pub async fn connect(
+        url: &str,
+        headers: &HashMap<String, String>,
+        capacity: usize,
+        log: &Log,
+    ) -> Result<(Self, Receiver<TransportEvent>), SignalRError> {
+        let mut request = url
+            .into_client_request()
+            .map_err(|e| format!("Invalid WebSocket URL: {}", e))?;
+
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("Invalid header `{}`: {}", name, e))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| format!("Invalid value for header `{}`: {}", name, e))?;
+
+            request.headers_mut().insert(name, value);
+        }
+
+        let (stream, _) = match tokio_tungstenite::connect_async(request).await {
+            Ok(connected) => connected,
+            Err(Error::Http(response)) if response.status() == StatusCode::UNAUTHORIZED => {
+                return Err(SignalRError::Unauthorized(
+                    "WebSocket upgrade was rejected with status 401".to_owned(),
+                ))
+            }
+            Err(e) => return Err(format!("Failed to create websocket: {}", e).into()),
+        };
+
+        let (mut write, mut read) = stream.split();
+        let (outgoing, mut outgoing_receiver) = mpsc::unbounded::<Message>();
+        let (mut sender, receiver) = mpsc::channel::<TransportEvent>(capacity);
+
+        let log = log.clone();
+        tokio::task::spawn_local(async move {
+            while let Some(message) = outgoing_receiver.next().await {
+                let closing = matches!(message, Message::Close(_));
+
+                if let Err(e) = write.send(message).await {
+                    log_error!(log, "Failed to send message: {}", e);
+                    break;
+                }
+
+                if closing {
+                    break;
+                }
+            }
+        });
+
+        let reader = tokio::spawn(async move {
+            let info = loop {
+                match read.next().await {
+                    Some(Ok(Message::Text(text))) => {
+                        if sender
+                            .send(TransportEvent::Text(text.to_string()))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Binary(data))) => {
+                        if sender
+                            .send(TransportEvent::Binary(data.to_vec()))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(frame))) => {
+                        break match frame {
+                            Some(frame) => CloseInfo {
+                                code: frame.code.into(),
+                                reason: frame.reason.to_string(),
+                                was_clean: true,
+                            },
+                            None => CloseInfo {
+                                code: CloseCode::Status.into(),
+                                reason: String::new(),
+                                was_clean: true,
+                            },
+                        };
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        break CloseInfo {
+                            code: ABNORMAL_CLOSURE,
+                            reason: e.to_string(),
+                            was_clean: false,
+                        }
+                    }
+                    None => {
+                        break CloseInfo {
+                            code: ABNORMAL_CLOSURE,
+                            reason: String::new(),
+                            was_clean: false,
+                        }
+                    }
+                }
+            };
+
+            let _ = sender.send(TransportEvent::Closed(info)).await;
+        });
+
+        Ok((Self { outgoing, reader }, receiver))
+    }
+
+    pub fn send_text(&self, text: &str) -> Result<(), String> {
+        self.outgoing
+            .unbounded_send(Message::Text(text.into()))
+            .map_err(|e| format!("Failed to send message: {}", e))
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> Result<(), String> {
+        self.outgoing
+            .unbounded_send(Message::Binary(data.to_vec().into()))
+            .map_err(|e| format!("Failed to send message: {}", e))
+    }
+
+    /// Closes the socket without reporting a `Closed` event.
+    pub fn close(&self, code: u16) {
+        self.reader.abort();
+
+        let frame = CloseFrame {
+            code: code.into(),
+            reason: "".into(),
+        };
+
+        // Fails if the writer has already stopped, in which case there's nothing to close.
+        let _ = self.outgoing.unbounded_send(Message::Close(Some(frame)));
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.close(NORMAL_CLOSURE);
+    }
+}
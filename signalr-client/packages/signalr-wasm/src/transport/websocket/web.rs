@@ -0,0 +1,193 @@
+use futures::channel::mpsc::{self, UnboundedReceiver};
+use futures::channel::oneshot;
+use futures::future::{select, Either};
+use futures::StreamExt;
+use js_sys::{Array, ArrayBuffer, Function, Object, Reflect, Uint8Array};
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
+
+use crate::error::{CloseInfo, SignalRError};
+use crate::log::Log;
+use crate::transport::{TransportEvent, NORMAL_CLOSURE};
+
+/// A `WebSocket`, from the browser or any object with the same interface, along with the
+/// closures handling its events. Dropping it detaches the closures and closes the socket.
+pub struct Socket {
+    ws: WebSocket,
+    log: Log,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+    _on_error: Closure<dyn FnMut(Event)>,
+}
+
+/// Creates a WebSocket, or any object with the same interface, for a URL and the configured
+/// headers.
+pub type WebSocketFactory = Rc<dyn Fn(&str, &HashMap<String, String>) -> Result<WebSocket, String>>;
+
+/// A factory calling `new constructor(url, [], { headers })`.
+pub fn constructor_factory(constructor: Function) -> WebSocketFactory {
+    Rc::new(move |url, headers| Socket::construct(&constructor, url, headers))
+}
+
+impl Socket {
+    /// Opens a socket from `factory`, or the global `WebSocket` if there isn't one, resolving
+    /// once it's open. Browsers don't allow custom headers on WebSocket upgrades, so `headers`
+    /// are only passed to a factory.
+    ///
+    /// Events are queued from the socket's callbacks as they fire, so they're read in the order
+    /// they arrived. The queue is unbounded, as there's no way to pause a `WebSocket`.
+    pub async fn connect(
+        url: &str,
+        headers: &HashMap<String, String>,
+        factory: Option<&WebSocketFactory>,
+        log: &Log,
+    ) -> Result<(Self, UnboundedReceiver<TransportEvent>), SignalRError> {
+        let ws = match factory {
+            Some(factory) => factory(url, headers)?,
+            None => Self::construct(&Self::global_constructor()?, url, &HashMap::new())?,
+        };
+        ws.set_binary_type(BinaryType::Arraybuffer);
+
+        let (sender, mut receiver) = mpsc::unbounded::<TransportEvent>();
+        let (open_sender, open_receiver) = oneshot::channel::<()>();
+
+        let on_open = Closure::once(move || {
+            // Only fails once `connect` has given up waiting.
+            let _ = open_sender.send(());
+        });
+
+        let message_sender = sender.clone();
+        let message_log = log.clone();
+        let on_message = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
+            let data = e.data();
+            let event = if let Some(text) = data.as_string() {
+                TransportEvent::Text(text)
+            } else if let Ok(buffer) = data.dyn_into::<ArrayBuffer>() {
+                TransportEvent::Binary(Uint8Array::new(&buffer).to_vec())
+            } else {
+                log_error!(
+                    message_log,
+                    "Failed to parse message: Unsupported wire format"
+                );
+                return;
+            };
+
+            if let Err(e) = message_sender.unbounded_send(event) {
+                log_error!(message_log, "Failed to send message: {}", e);
+            }
+        });
+
+        let close_log = log.clone();
+        let on_close = Closure::<dyn FnMut(_)>::new(move |e: CloseEvent| {
+            let info = CloseInfo::from(&e);
+
+            if let Err(e) = sender.unbounded_send(TransportEvent::Closed(info)) {
+                log_error!(close_log, "Failed to send close event: {}", e);
+            }
+        });
+
+        let error_log = log.clone();
+        let on_error = Closure::<dyn FnMut(_)>::new(move |_: Event| {
+            log_warn!(error_log, "WebSocket error");
+        });
+
+        ws.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        ws.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+        // Declared after `on_open`, so it detaches it before it's dropped.
+        let socket = Self {
+            ws,
+            log: log.clone(),
+            _on_message: on_message,
+            _on_close: on_close,
+            _on_error: on_error,
+        };
+
+        match select(open_receiver, receiver.next()).await {
+            Either::Left((Ok(()), _)) => {}
+            Either::Left((Err(e), _)) => {
+                return Err(format!("Failed to get open event: {}", e).into())
+            }
+            Either::Right((Some(TransportEvent::Closed(info)), _)) => {
+                return Err(SignalRError::SocketClosed(info))
+            }
+            Either::Right((event, _)) => {
+                return Err(format!("Unexpected event before open: {:?}", event).into())
+            }
+        }
+
+        socket.ws.set_onopen(None);
+
+        Ok((socket, receiver))
+    }
+
+    fn global_constructor() -> Result<Function, String> {
+        Reflect::get(&js_sys::global(), &JsValue::from("WebSocket"))
+            .ok()
+            .and_then(|constructor| constructor.dyn_into::<Function>().ok())
+            .ok_or_else(|| {
+                "There is no global WebSocket. Pass a WebSocket constructor, such as the one \
+                 from the `ws` package, to with_websocket_constructor or use with_websocket_factory"
+                    .to_owned()
+            })
+    }
+
+    /// Calls `new constructor(url, [], { headers })`, which is what the `ws` package expects.
+    /// Browsers ignore the extra arguments.
+    fn construct(
+        constructor: &Function,
+        url: &str,
+        headers: &HashMap<String, String>,
+    ) -> Result<WebSocket, String> {
+        let header_object = Object::new();
+        for (name, value) in headers {
+            Reflect::set(&header_object, &name.into(), &value.into())
+                .map_err(|e| format!("Invalid header `{}`: {:?}", name, e))?;
+        }
+
+        let options = Object::new();
+        Reflect::set(&options, &JsValue::from("headers"), &header_object)
+            .map_err(|e| format!("Failed to create websocket options: {:?}", e))?;
+
+        let arguments = Array::of3(&url.into(), &Array::new(), &options);
+
+        Reflect::construct(constructor, &arguments)
+            .map(JsCast::unchecked_into)
+            .map_err(|e| format!("Failed to create websocket: {:?}", e))
+    }
+
+    pub fn send_text(&self, text: &str) -> Result<(), String> {
+        self.ws
+            .send_with_str(text)
+            .map_err(|e| format!("Failed to send message: {:?}", e))
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> Result<(), String> {
+        self.ws
+            .send_with_u8_array(data)
+            .map_err(|e| format!("Failed to send message: {:?}", e))
+    }
+
+    /// Closes the socket without reporting a `Closed` event.
+    pub fn close(&self, code: u16) {
+        self.ws.set_onopen(None);
+        self.ws.set_onmessage(None);
+        self.ws.set_onclose(None);
+        self.ws.set_onerror(None);
+
+        if let Err(e) = self.ws.close_with_code(code) {
+            log_warn!(self.log, "Failed to close websocket: {:?}", e);
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.close(NORMAL_CLOSURE);
+    }
+}
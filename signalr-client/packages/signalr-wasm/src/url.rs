@@ -0,0 +1,314 @@
+/// A hub URL, accepted with an `http`, `https`, `ws` or `wss` scheme or relative to the
+/// current page, which can be turned into either the HTTP URL used for negotiation or the
+/// WebSocket URL used to open the socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HubUrl {
+    secure: bool,
+    authority: String,
+    path: String,
+    /// Without the leading `?`. Empty if there are no parameters.
+    query: String,
+}
+
+impl HubUrl {
+    /// Parses `url`, resolving it against the page's `location` if it's relative.
+    pub fn resolve(url: &str) -> Result<Self, String> {
+        Self::parse(url, location_href().as_deref())
+    }
+
+    pub fn is_absolute(url: &str) -> bool {
+        split_scheme(url.trim()).is_some()
+    }
+
+    pub fn parse(url: &str, base: Option<&str>) -> Result<Self, String> {
+        let url = url.trim();
+        let url = url.split('#').next().unwrap_or_default();
+
+        if let Some((scheme, rest)) = split_scheme(url) {
+            let secure = match scheme.to_ascii_lowercase().as_str() {
+                "http" | "ws" => false,
+                "https" | "wss" => true,
+                other => return Err(format!("Unsupported hub URL scheme `{}`", other)),
+            };
+
+            return Self::from_parts(secure, rest);
+        }
+
+        let base = match base {
+            Some(base) => Self::parse(base, None)?,
+            None => {
+                return Err(format!(
+                    "Cannot resolve relative hub URL `{}` without a page location",
+                    url
+                ))
+            }
+        };
+
+        if let Some(rest) = url.strip_prefix("//") {
+            return Self::from_parts(base.secure, rest);
+        }
+
+        let (path, query) = split_query(url);
+        if path.is_empty() {
+            // A query-only reference keeps the base path, as RFC 3986 section 5.2.2 says.
+            let query = if url.contains('?') {
+                query
+            } else {
+                &base.query
+            };
+
+            return Ok(Self {
+                query: query.to_owned(),
+                ..base
+            });
+        }
+
+        let path = if path.starts_with('/') {
+            path.to_owned()
+        } else {
+            let directory = match base.path.rfind('/') {
+                Some(index) => &base.path[..=index],
+                None => "/",
+            };
+
+            format!("{}{}", directory, path)
+        };
+
+        Ok(Self {
+            secure: base.secure,
+            authority: base.authority,
+            path: remove_dot_segments(&path),
+            query: query.to_owned(),
+        })
+    }
+
+    fn from_parts(secure: bool, rest: &str) -> Result<Self, String> {
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, path_and_query) = rest.split_at(authority_end);
+
+        if authority.is_empty() {
+            return Err("Hub URL is missing a host".to_owned());
+        }
+
+        let (path, query) = split_query(path_and_query);
+
+        Ok(Self {
+            secure,
+            authority: authority.to_owned(),
+            path: remove_dot_segments(path),
+            query: query.to_owned(),
+        })
+    }
+
+    /// Adds a query parameter, percent-encoding the name and value.
+    pub fn append_query(&mut self, name: &str, value: &str) {
+        if !self.query.is_empty() {
+            self.query.push('&');
+        }
+
+        self.query.push_str(&encode_component(name));
+        self.query.push('=');
+        self.query.push_str(&encode_component(value));
+    }
+
+    pub fn http_url(&self) -> String {
+        self.format(if self.secure { "https" } else { "http" })
+    }
+
+    pub fn ws_url(&self) -> String {
+        self.format(if self.secure { "wss" } else { "ws" })
+    }
+
+    /// The negotiate endpoint for this hub, keeping the hub URL's own query parameters.
+    pub fn negotiate_url(&self) -> String {
+        let mut negotiate = Self {
+            path: format!("{}/negotiate", self.path.trim_end_matches('/')),
+            query: String::new(),
+            ..self.clone()
+        };
+        negotiate.append_query("negotiateVersion", "1");
+
+        if !self.query.is_empty() {
+            negotiate.query.push('&');
+            negotiate.query.push_str(&self.query);
+        }
+
+        negotiate.http_url()
+    }
+
+    fn format(&self, scheme: &str) -> String {
+        let mut url = format!("{}://{}{}", scheme, self.authority, self.path);
+
+        if !self.query.is_empty() {
+            url.push('?');
+            url.push_str(&self.query);
+        }
+
+        url
+    }
+}
+
+fn split_scheme(url: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let valid = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+
+    valid.then_some((scheme, rest))
+}
+
+fn split_query(path_and_query: &str) -> (&str, &str) {
+    path_and_query
+        .split_once('?')
+        .unwrap_or((path_and_query, ""))
+}
+
+/// Resolves `.` and `..` segments, as described in RFC 3986 section 5.2.4.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+
+    for segment in path.split('/').skip(1) {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    if path.ends_with("/.") || path.ends_with("/..") {
+        segments.push("");
+    }
+
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", segments.join("/"))
+    }
+}
+
+fn encode_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    encoded
+}
+
+#[cfg(target_arch = "wasm32")]
+fn location_href() -> Option<String> {
+    use js_sys::Reflect;
+    use wasm_bindgen::JsValue;
+
+    let location = Reflect::get(&js_sys::global(), &JsValue::from("location")).ok()?;
+    if location.is_undefined() {
+        return None;
+    }
+
+    Reflect::get(&location, &JsValue::from("href"))
+        .ok()?
+        .as_string()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn location_href() -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = "https://example.com/app/chat/index.html?room=1#top";
+
+    fn resolve(url: &str) -> String {
+        HubUrl::parse(url, Some(PAGE)).unwrap().ws_url()
+    }
+
+    #[test]
+    fn schemes_map_to_websocket_schemes() {
+        for (url, expected) in [
+            ("http://example.com/hub", "ws://example.com/hub"),
+            ("https://example.com/hub", "wss://example.com/hub"),
+            ("ws://example.com/hub", "ws://example.com/hub"),
+            ("wss://example.com/hub", "wss://example.com/hub"),
+            ("HTTPS://example.com/hub", "wss://example.com/hub"),
+        ] {
+            assert_eq!(HubUrl::parse(url, None).unwrap().ws_url(), expected);
+        }
+
+        let url = HubUrl::parse("wss://example.com/hub", None).unwrap();
+        assert_eq!(url.http_url(), "https://example.com/hub");
+        assert!(HubUrl::parse("ftp://example.com/hub", None).is_err());
+    }
+
+    #[test]
+    fn relative_urls_resolve_against_the_page() {
+        assert_eq!(resolve("hub"), "wss://example.com/app/chat/hub");
+        assert_eq!(resolve("/hub"), "wss://example.com/hub");
+        assert_eq!(resolve("../hub"), "wss://example.com/app/hub");
+        assert_eq!(resolve("./a/../../../hub"), "wss://example.com/hub");
+        assert_eq!(resolve("//other.com/hub"), "wss://other.com/hub");
+        assert_eq!(resolve("?x=1"), "wss://example.com/app/chat/index.html?x=1");
+
+        assert!(HubUrl::parse("/hub", None).is_err());
+    }
+
+    #[test]
+    fn queries_are_kept_and_fragments_dropped() {
+        assert_eq!(
+            resolve("https://example.com/hub?tenant=a&b=2#section"),
+            "wss://example.com/hub?tenant=a&b=2"
+        );
+        assert_eq!(
+            resolve("hub?tenant=a#section"),
+            "wss://example.com/app/chat/hub?tenant=a"
+        );
+    }
+
+    #[test]
+    fn appended_query_parameters_are_percent_encoded() {
+        let mut url = HubUrl::parse("https://example.com/hub?tenant=a", None).unwrap();
+        url.append_query("id", "a b/c+d");
+        url.append_query("access_token", "x.y_z~=&?");
+
+        assert_eq!(
+            url.ws_url(),
+            "wss://example.com/hub?tenant=a&id=a%20b%2Fc%2Bd&access_token=x.y_z~%3D%26%3F"
+        );
+    }
+
+    #[test]
+    fn ipv6_authorities_are_kept() {
+        let url = HubUrl::parse("http://[::1]:5000/hub?x=1", None).unwrap();
+
+        assert_eq!(url.ws_url(), "ws://[::1]:5000/hub?x=1");
+        assert_eq!(
+            url.negotiate_url(),
+            "http://[::1]:5000/hub/negotiate?negotiateVersion=1&x=1"
+        );
+    }
+
+    #[test]
+    fn negotiate_urls_keep_the_hub_query() {
+        let url = HubUrl::parse("wss://example.com/hub/?tenant=a", None).unwrap();
+        assert_eq!(
+            url.negotiate_url(),
+            "https://example.com/hub/negotiate?negotiateVersion=1&tenant=a"
+        );
+
+        let url = HubUrl::parse("https://example.com/hub", None).unwrap();
+        assert_eq!(
+            url.negotiate_url(),
+            "https://example.com/hub/negotiate?negotiateVersion=1"
+        );
+    }
+}
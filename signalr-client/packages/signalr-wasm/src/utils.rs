@@ -8,3 +8,22 @@ pub fn set_panic_hook() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
 }
+
+/// 16 random hex digits. Not cryptographically secure, only unlikely to repeat.
+pub fn random_id() -> String {
+    #[cfg(target_arch = "wasm32")]
+    let bits = {
+        let half = || (js_sys::Math::random() * f64::from(u32::MAX)) as u64;
+        half() << 32 | half()
+    };
+
+    #[cfg(not(target_arch = "wasm32"))]
+    let bits = {
+        use std::hash::BuildHasher;
+
+        // Hash keys are seeded randomly per process and differ per `RandomState`.
+        std::collections::hash_map::RandomState::new().hash_one(std::time::SystemTime::now())
+    };
+
+    format!("{:016x}", bits)
+}
@@ -0,0 +1,119 @@
+//! Test suite for Node, run with `wasm-pack test --node`.
+
+#![cfg(target_arch = "wasm32")]
+
+extern crate wasm_bindgen_test;
+use js_sys::Function;
+use serde_json::json;
+use signalr_wasm::{ConnectionState, HubConnectionBuilder};
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_test::*;
+
+#[wasm_bindgen(inline_js = r#"
+export function fakeWebSocket(log) {
+    return class FakeWebSocket {
+        constructor(url, protocols, options) {
+            log.push(["constructed", url, options.headers]);
+            setTimeout(() => this.onopen && this.onopen({}), 0);
+        }
+
+        send(data) {
+            log.push(["sent", data]);
+            const message = JSON.parse(data.slice(0, -1));
+            let reply;
+
+            if (message.protocol) {
+                reply = {};
+            } else if (message.type === 1) {
+                reply = { type: 3, invocationId: message.invocationId, result: message.arguments[0] * 2 };
+            }
+
+            if (reply) {
+                setTimeout(() => this.onmessage && this.onmessage({ data: JSON.stringify(reply) + "\x1e" }), 0);
+            }
+        }
+
+        close(code) {
+            log.push(["closed", code]);
+        }
+    };
+}
+"#)]
+extern "C" {
+    #[wasm_bindgen(js_name = fakeWebSocket)]
+    fn fake_web_socket(log: &js_sys::Array) -> Function;
+}
+
+#[wasm_bindgen_test]
+async fn connects_with_a_websocket_constructor() {
+    let log = js_sys::Array::new();
+    let connection = HubConnectionBuilder::new()
+        .with_url("http://localhost:5095/chatHub")
+        .with_header("X-Client", "node")
+        .with_websocket_constructor(fake_web_socket(&log))
+        .build()
+        .unwrap();
+
+    connection.connect().await.unwrap();
+    assert_eq!(connection.state(), ConnectionState::Connected);
+
+    let result = connection
+        .send_invocation("Double".to_owned(), vec![json!(21)])
+        .await;
+    assert_eq!(result, Ok(json!(42)));
+
+    connection.stop(true).await.unwrap();
+
+    let constructed = js_sys::Array::from(&log.get(0));
+    assert_eq!(
+        constructed.get(1).as_string().as_deref(),
+        Some("ws://localhost:5095/chatHub")
+    );
+    let headers = constructed.get(2);
+    assert_eq!(
+        js_sys::Reflect::get(&headers, &"X-Client".into())
+            .unwrap()
+            .as_string()
+            .as_deref(),
+        Some("node")
+    );
+}
+
+#[wasm_bindgen_test]
+async fn connects_with_a_websocket_factory() {
+    let log = js_sys::Array::new();
+    let constructor = fake_web_socket(&log);
+    let urls = Rc::new(RefCell::new(Vec::new()));
+    let connection = HubConnectionBuilder::new()
+        .with_url("https://localhost:5095/chatHub")
+        .with_websocket_factory({
+            let urls = urls.clone();
+            move |url, headers| {
+                urls.borrow_mut().push(url.to_owned());
+
+                let options = js_sys::Object::new();
+                js_sys::Reflect::set(&options, &"headers".into(), &js_sys::Object::new()).unwrap();
+                assert!(headers.is_empty());
+
+                js_sys::Reflect::construct(
+                    &constructor,
+                    &js_sys::Array::of3(&url.into(), &js_sys::Array::new(), &options),
+                )
+                .map(JsCast::unchecked_into)
+                .map_err(|e| format!("{:?}", e))
+            }
+        })
+        .build()
+        .unwrap();
+
+    connection.connect().await.unwrap();
+    assert_eq!(connection.state(), ConnectionState::Connected);
+    connection.stop(true).await.unwrap();
+
+    assert_eq!(
+        *urls.borrow(),
+        vec!["wss://localhost:5095/chatHub".to_owned()]
+    );
+}